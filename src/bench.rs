@@ -0,0 +1,69 @@
+//! `bench`: times preprocessing and inference separately over repeated runs.
+
+use std::time::{Duration, Instant};
+
+use image::DynamicImage;
+use wd14_tagger::Predictor;
+
+use crate::ThresholdArgs;
+
+/// Tags `img` `iterations` times after one untimed warm-up run and prints
+/// the mean and median time of each phase.
+pub fn run(
+    pred: &mut Predictor,
+    img: &DynamicImage,
+    iterations: usize,
+    thresholds: &ThresholdArgs,
+) -> wd14_tagger::Result<()> {
+    let config = thresholds.config();
+    let filter = &thresholds.filter;
+    let preprocessor = pred.preprocessor()?;
+
+    // The first run pays for lazy allocation and, on GPUs, kernel selection.
+    pred.predict_prepared(preprocessor.prepare(img)?, &config, filter)?;
+
+    let mut preprocess = Vec::with_capacity(iterations);
+    let mut inference = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let input = preprocessor.prepare(img)?;
+        let prepared = Instant::now();
+        pred.predict_prepared(input, &config, filter)?;
+        preprocess.push(prepared - start);
+        inference.push(prepared.elapsed());
+    }
+    let total: Vec<Duration> = preprocess
+        .iter()
+        .zip(&inference)
+        .map(|(a, b)| *a + *b)
+        .collect();
+
+    println!("{} iterations", iterations);
+    println!("{:<12} {:>12} {:>12}", "phase", "mean", "median");
+    for (name, times) in [
+        ("preprocess", preprocess),
+        ("inference", inference),
+        ("total", total),
+    ] {
+        let (mean, median) = summarise(times);
+        println!(
+            "{:<12} {:>9.2} ms {:>9.2} ms",
+            name,
+            mean.as_secs_f64() * 1e3,
+            median.as_secs_f64() * 1e3
+        );
+    }
+    Ok(())
+}
+
+fn summarise(mut times: Vec<Duration>) -> (Duration, Duration) {
+    times.sort();
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+    let mid = times.len() / 2;
+    let median = if times.len().is_multiple_of(2) {
+        (times[mid - 1] + times[mid]) / 2
+    } else {
+        times[mid]
+    };
+    (mean, median)
+}
@@ -0,0 +1,79 @@
+//! On-disk cache of preprocessed input tensors, so re-tagging a dataset with
+//! new thresholds skips decoding and resizing every image again.
+
+use hmac_sha256::Hash;
+use ndarray::Array4;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use wd14_tagger::Preprocessor;
+
+use crate::decode::DecodeOptions;
+
+/// Tensors stored as `<sha256>.tensor` files: four little-endian `u64`
+/// dimensions followed by the little-endian `f32` data.
+pub struct TensorCache {
+    dir: PathBuf,
+    /// Everything besides the file contents that shapes the tensor.
+    settings: String,
+}
+
+impl TensorCache {
+    pub fn new(dir: &Path, preprocessor: &Preprocessor, decode: DecodeOptions) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(TensorCache {
+            dir: dir.to_path_buf(),
+            // The Debug forms cover the input size, layout and options.
+            settings: format!("{:?} {:?}", preprocessor, decode),
+        })
+    }
+
+    fn path(&self, bytes: &[u8]) -> PathBuf {
+        let mut hash = Hash::new();
+        hash.update(self.settings.as_bytes());
+        hash.update([0]);
+        hash.update(bytes);
+        let hex: String = hash
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir.join(hex + ".tensor")
+    }
+
+    /// The cached tensor for a file with contents `bytes`, if any. Unreadable
+    /// or truncated entries count as misses.
+    pub fn get(&self, bytes: &[u8]) -> Option<Array4<f32>> {
+        let data = fs::read(self.path(bytes)).ok()?;
+        let (header, body) = data.split_at_checked(32)?;
+        let mut shape = [0usize; 4];
+        for (dim, chunk) in shape.iter_mut().zip(header.chunks_exact(8)) {
+            *dim = u64::from_le_bytes(chunk.try_into().ok()?) as usize;
+        }
+        let values: Vec<f32> = body
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().expect("chunks of 4")))
+            .collect();
+        Array4::from_shape_vec(shape, values).ok()
+    }
+
+    pub fn put(&self, bytes: &[u8], tensor: &Array4<f32>) -> io::Result<()> {
+        let mut data = Vec::with_capacity(32 + tensor.len() * 4);
+        for &dim in tensor.shape() {
+            data.extend_from_slice(&(dim as u64).to_le_bytes());
+        }
+        for value in tensor.iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        // Write then rename, so a concurrent or interrupted run never sees a
+        // partial entry.
+        let path = self.path(bytes);
+        let tmp = path.with_extension(format!(
+            "tmp.{}.{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)
+    }
+}
@@ -0,0 +1,185 @@
+//! `--config`: tagging profiles read from a TOML or JSON file, so a set of
+//! model and threshold options can be shared instead of retyped.
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use wd14_tagger::ModelKind;
+
+use crate::{check_threshold, Args, OutputFormat};
+
+/// The options a profile may set, named like their command-line flags,
+/// e.g. `general-threshold = 0.4`. Options given on the command line win;
+/// one that picks how general or character tags are selected overrides
+/// every profile option of that kind, as some take precedence over others.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    model: Option<String>,
+    wd14_model: Option<Vec<String>>,
+    general_threshold: Option<f32>,
+    character_threshold: Option<f32>,
+    rating_threshold: Option<f32>,
+    relative_threshold: Option<f32>,
+    general_mcut: Option<bool>,
+    character_mcut: Option<bool>,
+    character_mcut_floor: Option<f32>,
+    top_k: Option<usize>,
+    format: Option<String>,
+}
+
+impl Config {
+    /// Reads `path` as TOML if it ends in `.toml`, else as JSON.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let value = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml_to_json(&text)?
+        } else {
+            serde_json::from_str(&text).map_err(|e| e.to_string())?
+        };
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    /// Sets each option of `args` that this profile has and `matches` shows
+    /// wasn't given on the command line, leaving the general or character
+    /// selection alone entirely if the command line set any of it.
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<(), String> {
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        let all_unset = |ids: &[&str]| ids.iter().all(|id| unset(id));
+        let general = all_unset(&GENERAL_SELECTION);
+        let character = all_unset(&CHARACTER_SELECTION);
+        let threshold =
+            |key: &str, value: f32| check_threshold(value).map_err(|e| format!("`{}`: {}", key, e));
+
+        if let Some(model) = self.model.filter(|_| unset("model")) {
+            args.model.model =
+                ModelKind::from_str(&model, false).map_err(|e| format!("`model`: {}", e))?;
+        }
+        if let Some(models) = self.wd14_model.filter(|_| unset("wd14_model")) {
+            if !models.is_empty() {
+                args.model.wd14_model = models;
+            }
+        }
+        let thresholds = &mut args.thresholds;
+        if let Some(value) = self.general_threshold.filter(|_| general) {
+            thresholds.general_threshold = threshold("general-threshold", value)?;
+        }
+        if let Some(value) = self.character_threshold.filter(|_| character) {
+            thresholds.character_threshold = threshold("character-threshold", value)?;
+        }
+        if let Some(value) = self.rating_threshold.filter(|_| unset("rating_threshold")) {
+            thresholds.rating_threshold = Some(threshold("rating-threshold", value)?);
+        }
+        if let Some(value) = self.relative_threshold.filter(|_| general) {
+            thresholds.relative_threshold = Some(threshold("relative-threshold", value)?);
+        }
+        if let Some(value) = self.general_mcut.filter(|_| general) {
+            thresholds.general_mcut = value;
+        }
+        if let Some(value) = self.character_mcut.filter(|_| character) {
+            thresholds.character_mcut = value;
+        }
+        if let Some(value) = self
+            .character_mcut_floor
+            .filter(|_| unset("character_mcut_floor"))
+        {
+            thresholds.character_mcut_floor = threshold("character-mcut-floor", value)?;
+        }
+        if let Some(value) = self.top_k.filter(|_| general) {
+            thresholds.top_k = Some(value);
+        }
+        if let Some(format) = self.format.filter(|_| unset("format")) {
+            args.format =
+                OutputFormat::from_str(&format, false).map_err(|e| format!("`format`: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The flags that together pick how general tags are selected; see
+/// `ThresholdArgs::config` for which wins.
+const GENERAL_SELECTION: [&str; 5] = [
+    "general_threshold",
+    "general_mcut",
+    "relative_threshold",
+    "top_k",
+    "no_general",
+];
+
+/// The flags that together pick how character tags are selected.
+const CHARACTER_SELECTION: [&str; 3] = ["character_threshold", "character_mcut", "no_characters"];
+
+/// Converts a TOML document of top-level keys to the equivalent JSON, so
+/// both formats share one deserializer.
+fn toml_to_json(text: &str) -> Result<Value, String> {
+    let doc: toml_edit::Document = text.parse().map_err(|e| format!("{}", e))?;
+    let mut object = serde_json::Map::new();
+    for (key, item) in doc.iter() {
+        let value = item
+            .as_value()
+            .ok_or_else(|| format!("`{}`: tables are not supported", key))?;
+        object.insert(key.to_string(), toml_value(key, value)?);
+    }
+    Ok(Value::Object(object))
+}
+
+fn toml_value(key: &str, value: &toml_edit::Value) -> Result<Value, String> {
+    use toml_edit::Value as Toml;
+    Ok(match value {
+        Toml::String(s) => Value::from(s.value().as_str()),
+        Toml::Integer(n) => Value::from(*n.value()),
+        Toml::Float(n) => serde_json::Number::from_f64(*n.value())
+            .map(Value::Number)
+            .ok_or_else(|| format!("`{}`: {} is not a finite number", key, n.value()))?,
+        Toml::Boolean(b) => Value::from(*b.value()),
+        Toml::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| toml_value(key, item))
+                .collect::<Result<_, _>>()?,
+        ),
+        Toml::Datetime(_) | Toml::InlineTable(_) => {
+            return Err(format!("`{}`: unsupported value {}", key, value))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// `args` parsed from `cli`, then `profile` applied over them.
+    fn apply(cli: &[&str], profile: &str) -> Args {
+        let matches = Args::command()
+            .try_get_matches_from([&["wd14-tagger", "image.png"], cli].concat())
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        let config: Config = serde_json::from_str(profile).unwrap();
+        config.apply(&mut args, &matches).unwrap();
+        args
+    }
+
+    #[test]
+    fn command_line_selection_overrides_the_whole_profile_selection() {
+        let profile = r#"{"top-k": 20, "relative-threshold": 0.5, "character-mcut": true}"#;
+
+        let args = apply(&["--general-threshold", "0.5"], profile);
+        assert_eq!(args.thresholds.top_k, None);
+        assert_eq!(args.thresholds.relative_threshold, None);
+        assert_eq!(args.thresholds.general_threshold, 0.5);
+        assert!(args.thresholds.character_mcut);
+
+        let args = apply(&["--general-mcut", "--character-threshold", "0.9"], profile);
+        assert_eq!(args.thresholds.top_k, None);
+        assert!(args.thresholds.general_mcut);
+        assert!(!args.thresholds.character_mcut);
+
+        let args = apply(&[], profile);
+        assert_eq!(args.thresholds.top_k, Some(20));
+        assert_eq!(args.thresholds.relative_threshold, Some(0.5));
+    }
+}
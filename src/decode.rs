@@ -0,0 +1,275 @@
+//! Image decoding for the CLI and the server, with frame selection for
+//! animated formats, optional EXIF orientation and readable errors for
+//! unsupported ones.
+
+use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+use image::{AnimationDecoder, DynamicImage, ImageError, ImageFormat};
+use std::io::Cursor;
+
+/// Formats `decode_image` can read, for error messages.
+pub const SUPPORTED_FORMATS: &str = "PNG, JPEG, GIF, WebP, BMP, TIFF, ICO, TGA, DDS, HDR, \
+                                     OpenEXR, PNM, farbfeld and QOI";
+
+/// How to decode an image.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeOptions {
+    /// Frame (0-based) of an animated GIF, WebP or APNG. Still images only
+    /// have frame 0.
+    pub frame: usize,
+    /// Turn the image upright according to its EXIF orientation (JPEG, PNG
+    /// and WebP), as photo viewers do.
+    pub respect_exif: bool,
+}
+
+/// Decodes `bytes` as `options` say.
+pub fn decode_image(bytes: &[u8], options: DecodeOptions) -> Result<DynamicImage, String> {
+    let img = decode_frame(bytes, options.frame)?;
+    if !options.respect_exif {
+        return Ok(img);
+    }
+    Ok(match exif_orientation(bytes) {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    })
+}
+
+fn decode_frame(bytes: &[u8], frame: usize) -> Result<DynamicImage, String> {
+    let format = image::guess_format(bytes).map_err(|_| {
+        format!(
+            "unrecognised image format (supported: {})",
+            SUPPORTED_FORMATS
+        )
+    })?;
+
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))
+            .map_err(describe)?
+            .into_frames(),
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes)).map_err(describe)?;
+            if !decoder.has_animation() {
+                return still(bytes, format, frame);
+            }
+            decoder.into_frames()
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes)).map_err(describe)?;
+            if !decoder.is_apng() {
+                return still(bytes, format, frame);
+            }
+            decoder.apng().into_frames()
+        }
+        _ => return still(bytes, format, frame),
+    };
+
+    let mut count = 0;
+    for decoded in frames {
+        let decoded = decoded.map_err(describe)?;
+        if count == frame {
+            return Ok(DynamicImage::ImageRgba8(decoded.into_buffer()));
+        }
+        count += 1;
+    }
+    Err(format!(
+        "frame {} requested but the animation has {} frame(s)",
+        frame, count
+    ))
+}
+
+fn still(bytes: &[u8], format: ImageFormat, frame: usize) -> Result<DynamicImage, String> {
+    if frame > 0 {
+        return Err(format!(
+            "frame {} requested but the image is not animated",
+            frame
+        ));
+    }
+    image::load_from_memory_with_format(bytes, format).map_err(describe)
+}
+
+fn describe(e: ImageError) -> String {
+    match e {
+        ImageError::Unsupported(e) => format!("{} (supported formats: {})", e, SUPPORTED_FORMATS),
+        e => e.to_string(),
+    }
+}
+
+/// The EXIF orientation (1-8) of a JPEG, PNG or WebP file, if it has one.
+fn exif_orientation(bytes: &[u8]) -> Option<u16> {
+    let exif = match image::guess_format(bytes).ok()? {
+        ImageFormat::Jpeg => jpeg_exif(bytes)?,
+        ImageFormat::Png => png_exif(bytes)?,
+        ImageFormat::WebP => webp_exif(bytes)?,
+        _ => return None,
+    };
+    tiff_orientation(exif)
+}
+
+/// The TIFF data of a JPEG's `APP1` Exif segment.
+fn jpeg_exif(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2; // past SOI
+    while bytes.get(pos)? == &0xFF {
+        let marker = *bytes.get(pos + 1)?;
+        // Start of scan: the metadata segments are all before it.
+        if marker == 0xDA {
+            return None;
+        }
+        let len = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+        let segment = bytes.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xE1 {
+            if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                return Some(tiff);
+            }
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+/// The TIFF data of a PNG's `eXIf` chunk.
+fn png_exif(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 8; // past the signature
+    loop {
+        let len = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = bytes.get(pos + 4..pos + 8)?;
+        match kind {
+            b"eXIf" => return bytes.get(pos + 8..pos + 8 + len),
+            b"IDAT" | b"IEND" => return None,
+            _ => pos += 12 + len, // length, type, data and CRC
+        }
+    }
+}
+
+/// The TIFF data of a WebP's `EXIF` chunk.
+fn webp_exif(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 12; // past the RIFF header
+    loop {
+        let kind = bytes.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let data = bytes.get(pos + 8..pos + 8 + len)?;
+        if kind == b"EXIF" {
+            // Some writers keep the JPEG-style prefix.
+            return Some(data.strip_prefix(b"Exif\0\0").unwrap_or(data));
+        }
+        pos += 8 + len + len % 2; // chunks are padded to even sizes
+    }
+}
+
+/// Reads the Orientation entry (tag 0x0112) of the first IFD of EXIF
+/// `tiff` data.
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let u16_at = |i: usize| -> Option<u16> {
+        let b = [*tiff.get(i)?, *tiff.get(i + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let u32_at = |i: usize| -> Option<u32> {
+        let b: [u8; 4] = tiff.get(i..i + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    };
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|n| ifd + 2 + n * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EXIF TIFF data holding just an Orientation entry.
+    fn exif(orientation: u16, big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |v: u16| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let u32_bytes = |v: u32| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let mut tiff = if big_endian {
+            b"MM".to_vec()
+        } else {
+            b"II".to_vec()
+        };
+        tiff.extend_from_slice(&u16_bytes(42));
+        tiff.extend_from_slice(&u32_bytes(8)); // first IFD
+        tiff.extend_from_slice(&u16_bytes(1)); // one entry
+        tiff.extend_from_slice(&u16_bytes(0x0112));
+        tiff.extend_from_slice(&u16_bytes(3)); // SHORT
+        tiff.extend_from_slice(&u32_bytes(1));
+        tiff.extend_from_slice(&u16_bytes(orientation));
+        tiff.extend_from_slice(&[0, 0]);
+        tiff.extend_from_slice(&u32_bytes(0)); // no next IFD
+        tiff
+    }
+
+    /// A 16x8 JPEG, white on the left half and black on the right, with an
+    /// `APP1` Exif segment giving `orientation`.
+    fn rotated_jpeg(orientation: u16) -> Vec<u8> {
+        let img =
+            image::GrayImage::from_fn(16, 8, |x, _| image::Luma([if x < 8 { 255 } else { 0 }]));
+        let mut jpeg = Cursor::new(vec![]);
+        DynamicImage::ImageLuma8(img)
+            .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(95))
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+        let segment = [b"Exif\0\0".as_slice(), &exif(orientation, false)].concat();
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        app1.extend_from_slice(&segment);
+        [&jpeg[..2], &app1, &jpeg[2..]].concat()
+    }
+
+    #[test]
+    fn tiff_orientation_reads_either_byte_order() {
+        assert_eq!(tiff_orientation(&exif(6, false)), Some(6));
+        assert_eq!(tiff_orientation(&exif(8, true)), Some(8));
+        assert_eq!(tiff_orientation(b"XX\x2a\0\x08\0\0\0"), None);
+        assert_eq!(tiff_orientation(&exif(6, false)[..12]), None);
+    }
+
+    #[test]
+    fn decode_image_turns_a_rotated_jpeg_upright() {
+        let jpeg = rotated_jpeg(6);
+        assert_eq!(exif_orientation(&jpeg), Some(6));
+
+        let as_stored = decode_image(&jpeg, DecodeOptions::default()).unwrap();
+        assert_eq!((as_stored.width(), as_stored.height()), (16, 8));
+
+        let options = DecodeOptions {
+            respect_exif: true,
+            ..DecodeOptions::default()
+        };
+        let upright = decode_image(&jpeg, options).unwrap().to_luma8();
+        assert_eq!(upright.dimensions(), (8, 16));
+        // Turned clockwise, the white left half ends up on top.
+        assert!(upright.get_pixel(4, 3)[0] > 200);
+        assert!(upright.get_pixel(4, 12)[0] < 55);
+    }
+}
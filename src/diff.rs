@@ -0,0 +1,44 @@
+//! `diff`: compares the general tags of two images.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use wd14_tagger::Prediction;
+
+/// Prints the general tags kept for only one of the two images, then the
+/// score change of each shared tag, largest change first, with scores to
+/// `decimals` places.
+pub fn print(a_path: &Path, a: &Prediction, b_path: &Path, b: &Prediction, decimals: usize) {
+    let a_scores: HashMap<&str, f32> = a.general.iter().map(|(t, s)| (t.as_str(), *s)).collect();
+    let b_scores: HashMap<&str, f32> = b.general.iter().map(|(t, s)| (t.as_str(), *s)).collect();
+
+    for (path, tags, other) in [(a_path, a, &b_scores), (b_path, b, &a_scores)] {
+        let only: Vec<String> = tags
+            .general
+            .iter()
+            .filter(|(tag, _)| !other.contains_key(tag.as_str()))
+            .map(|(tag, score)| format!("{} ({:.*})", tag, decimals, score))
+            .collect();
+        println!("Only in {}: {}", path.display(), only.join(", "));
+    }
+
+    let mut shared: Vec<(&str, f32, f32)> = a
+        .general
+        .iter()
+        .filter_map(|(tag, score)| Some((tag.as_str(), *score, *b_scores.get(tag.as_str())?)))
+        .collect();
+    shared.sort_by(|x, y| (y.2 - y.1).abs().total_cmp(&(x.2 - x.1).abs()));
+    println!("Shared: {}", shared.len());
+    for (tag, a_score, b_score) in shared {
+        println!(
+            "  {}: {:.*} -> {:.*} ({:+.*})",
+            tag,
+            decimals,
+            a_score,
+            decimals,
+            b_score,
+            decimals,
+            b_score - a_score
+        );
+    }
+}
@@ -0,0 +1,80 @@
+#[cfg(feature = "hub")]
+use hf_hub::api::sync::ApiError;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Everything that can go wrong while loading a model or tagging an image.
+#[derive(Debug, Error)]
+pub enum TaggerError {
+    #[cfg(feature = "hub")]
+    #[error("failed to initialise the Hugging Face hub client: {0}")]
+    Hub(#[source] ApiError),
+
+    #[cfg(feature = "hub")]
+    #[error("failed to fetch '{file}' from '{repo}': {source}")]
+    Download {
+        repo: String,
+        file: String,
+        #[source]
+        source: ApiError,
+    },
+
+    #[error("'{file}' from '{repo}' is not in the local Hugging Face cache")]
+    NotCached { repo: String, file: String },
+
+    #[error("failed to read '{}': {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("malformed tag CSV '{}': {source}", path.display())]
+    Csv {
+        path: PathBuf,
+        #[source]
+        source: csv::Error,
+    },
+
+    #[error("malformed tag vocabulary '{}': {source}", path.display())]
+    Vocab {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error(
+        "built without the `hub` feature; load the model with \
+         Predictor::with_local_files or Predictor::with_model_bytes"
+    )]
+    HubDisabled,
+
+    #[error("the model is not loaded; call Predictor::load first")]
+    NotLoaded,
+
+    #[error("ONNX Runtime error: {0}")]
+    Ort(#[from] ort::Error),
+
+    #[error("unexpected model output shape: {0}")]
+    Shape(#[from] ndarray::ShapeError),
+
+    #[error("failed to decode image: {0}")]
+    Decode(#[source] image::ImageError),
+
+    #[error("image is empty ({width}x{height} pixels)")]
+    EmptyImage { width: u32, height: u32 },
+
+    #[error("no views of the image to average")]
+    NoViews,
+
+    #[error(
+        "the model's input {shape:?} has a dynamic image size; set one with \
+         Predictor::with_input_size"
+    )]
+    DynamicInputSize { shape: Vec<i64> },
+
+    #[error("unsupported model: {0}")]
+    UnsupportedModel(String),
+}
+
+pub type Result<T, E = TaggerError> = std::result::Result<T, E>;
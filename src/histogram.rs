@@ -0,0 +1,41 @@
+//! `--histogram`: how one image's general tag scores spread over 0.0..=1.0,
+//! from the raw scores before any threshold.
+
+use wd14_tagger::{RawPrediction, ThresholdConfig};
+
+/// Ranges of width 0.1 from 0.0 to 1.0.
+const BUCKETS: usize = 10;
+
+/// Width of the longest bar, in characters.
+const BAR_WIDTH: usize = 50;
+
+/// Prints the number of general tags scoring in each tenth of 0.0..=1.0,
+/// with bars scaled to the largest bucket. Scores are rescaled by the
+/// temperature of `thresholds` first, so they match the ones tagging
+/// compares. A non-empty bucket always gets at least one `#`, so the sparse
+/// high-score ranges still show.
+pub fn print(raw: &RawPrediction, thresholds: &ThresholdConfig) {
+    let scores = raw.adjusted_scores(thresholds);
+    let mut counts = [0usize; BUCKETS];
+    for &i in &raw.general {
+        let score = scores[i].clamp(0.0, 1.0);
+        counts[((score * BUCKETS as f32) as usize).min(BUCKETS - 1)] += 1;
+    }
+    let max = counts.iter().copied().max().unwrap_or(0).max(1);
+
+    println!("{:<7} {:>6}", "score", "tags");
+    for (bucket, &count) in counts.iter().enumerate() {
+        let bar = if count == 0 {
+            0
+        } else {
+            (count * BAR_WIDTH).div_ceil(max)
+        };
+        println!(
+            "{:.1}-{:.1} {:>6} {}",
+            bucket as f32 / BUCKETS as f32,
+            (bucket + 1) as f32 / BUCKETS as f32,
+            count,
+            "#".repeat(bar)
+        );
+    }
+}
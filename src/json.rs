@@ -0,0 +1,54 @@
+//! JSON views of a [`Prediction`], shared by the CLI output and the server.
+
+use serde::Serialize;
+use wd14_tagger::{AppliedThresholds, Prediction};
+
+#[derive(Serialize)]
+pub struct TagScore<'a> {
+    tag: &'a str,
+    score: f32,
+}
+
+impl<'a> From<&'a (String, f32)> for TagScore<'a> {
+    fn from((tag, score): &'a (String, f32)) -> Self {
+        TagScore { tag, score: *score }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JsonOutput<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rating: Option<TagScore<'a>>,
+    ratings: Vec<TagScore<'a>>,
+    general: Vec<TagScore<'a>>,
+    characters: Vec<TagScore<'a>>,
+    thresholds: AppliedThresholds,
+    /// `[width, height]` of the model input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_size: Option<[usize; 2]>,
+}
+
+impl<'a> JsonOutput<'a> {
+    pub fn new(prediction: &'a Prediction, file: Option<String>) -> Self {
+        JsonOutput {
+            file,
+            rating: prediction.rating.as_ref().map(TagScore::from),
+            ratings: prediction.ratings.iter().map(TagScore::from).collect(),
+            general: prediction.general.iter().map(TagScore::from).collect(),
+            characters: prediction.characters.iter().map(TagScore::from).collect(),
+            thresholds: prediction.thresholds,
+            input_size: prediction.input_size.map(|(w, h)| [w, h]),
+        }
+    }
+}
+
+pub fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string_pretty(value).expect("JSON serialisation cannot fail")
+}
+
+/// Compact, single-line JSON, for appending one result per line.
+pub fn to_json_line<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("JSON serialisation cannot fail")
+}
@@ -0,0 +1,31 @@
+//! Image tagging with WD14 and DINOv3 ONNX tagger models.
+//!
+//! The [`Predictor`] downloads a model from the Hugging Face hub on first use
+//! and tags in-memory [`image::DynamicImage`] values:
+//!
+//! ```no_run
+//! use wd14_tagger::{ModelKind, Predictor, TagFilter, ThresholdConfig};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut pred = Predictor::new(ModelKind::Wd14);
+//! let img = image::open("image.png")?;
+//! let prediction = pred.predict(&img, &ThresholdConfig::default(), &TagFilter::default())?;
+//! println!("{}", prediction.general_string);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod error;
+pub mod predictor;
+
+pub use error::{Result, TaggerError};
+#[cfg(feature = "embedded-tags")]
+pub use predictor::embedded_wd14_labels;
+pub use predictor::{
+    escape_parens, hflip_views, load_category_map, load_dino_labels, load_implications,
+    load_tag_list, load_wd14_labels, tta_views, AppliedThresholds, Category, CategoryMap,
+    ChannelOrder, Device, Fit, Labels, ModelInfo, ModelKind, Normalize, OptimizationLevel,
+    OutputSelector, Prediction, Predictor, PreprocessOptions, Preprocessor, RawPrediction,
+    ResizeFilter, TagFilter, TagOrder, TagStyle, Threshold, ThresholdConfig, Wd14TagRow,
+    MAX_SCORE_PRECISION,
+};
@@ -0,0 +1,58 @@
+//! A minimal stderr backend for the `log` facade, plus the bridge that
+//! forwards ONNX Runtime's own messages into it.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Arc;
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "Error",
+            Level::Warn => "Warning",
+            Level::Info => "Info",
+            Level::Debug => "Debug",
+            Level::Trace => "Trace",
+        };
+        eprintln!("{}: {}", level, record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sends log records at or above `level` to stderr, and routes ONNX Runtime
+/// logging through them. Call once, before any session is built.
+pub fn init(level: LevelFilter) {
+    log::set_logger(&StderrLogger).expect("logger is only installed once");
+    log::set_max_level(level);
+
+    ort::init()
+        .with_logger(Arc::new(
+            |ort_level: ort::logging::LogLevel,
+             category: &str,
+             _id: &str,
+             code_location: &str,
+             message: &str| {
+                // One level down: ORT warns about things like unassigned
+                // nodes that users can't act on, and its errors also come
+                // back as `Result`s.
+                let level = match ort_level {
+                    ort::logging::LogLevel::Verbose => Level::Trace,
+                    ort::logging::LogLevel::Info => Level::Debug,
+                    ort::logging::LogLevel::Warning => Level::Info,
+                    ort::logging::LogLevel::Error => Level::Warn,
+                    ort::logging::LogLevel::Fatal => Level::Error,
+                };
+                log::log!(target: "ort", level, "ort [{}] {}: {}", category, code_location, message);
+            },
+        ))
+        .commit();
+}
@@ -1,69 +1,1964 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use image::{DynamicImage, ImageFormat};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn, LevelFilter};
+use ndarray::Array4;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{fs::File, io::Write, process::exit};
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, Read, Write},
+    process::exit,
+    time::{Duration, Instant},
+};
 
-mod predictor;
+use wd14_tagger::{
+    escape_parens, hflip_views, load_category_map, load_implications, load_tag_list,
+    predictor::{WD14_DEFAULT_MODEL, WD14_KNOWN_MODELS, WD14_REPO},
+    tta_views, ChannelOrder, Device, Fit, ModelKind, Normalize, OptimizationLevel, OutputSelector,
+    Prediction, Predictor, PreprocessOptions, RawPrediction, ResizeFilter, TagFilter, TagOrder,
+    TagStyle, TaggerError, Threshold, ThresholdConfig, MAX_SCORE_PRECISION,
+};
 
-use predictor::{ModelKind, Predictor};
+mod bench;
+mod cache;
+mod config;
+mod decode;
+mod diff;
+mod histogram;
+mod json;
+mod logging;
+mod server;
+mod sweep;
+
+use cache::TensorCache;
+use config::Config;
+use decode::{decode_image, DecodeOptions};
+use json::{to_json, to_json_line, JsonOutput};
 
 /// CLI to tag an image using ONNX model
 #[derive(Parser, Debug)]
 #[command(name = "ImageTagger")]
 #[command(about = "W14 Image Tagger", long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Path to the image file
-    image: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Model backend to use
-    #[arg(short = 'm', long = "model", default_value = "wd14")]
-    model: ModelKind,
+    /// Path to the image file, a directory of images to tag, an `http://` or
+    /// `https://` URL to download the image from, or `-` to read the image
+    /// from stdin. Several may be given to tag them all with one model load
+    #[arg(
+        required_unless_present_any = ["input_list", "dry_run"],
+        conflicts_with = "input_list"
+    )]
+    image: Vec<String>,
+
+    /// Extensions of the files to tag in a directory, comma-separated, e.g.
+    /// `png,jpg` [default: every format the decoder reads]
+    #[arg(long = "ext", value_name = "EXT", value_delimiter = ',')]
+    ext: Vec<String>,
+
+    /// Tag the images listed in this file, one path per line, in order;
+    /// blank lines and lines starting with `#` are skipped
+    #[arg(long = "input-list", value_name = "FILE", conflicts_with = "raw")]
+    input_list: Option<PathBuf>,
+
+    /// Load the model and print its tensors and tag counts instead of
+    /// tagging anything, to check the model and cache setup
+    #[arg(long = "dry-run", conflicts_with_all = ["image", "input_list"])]
+    dry_run: bool,
+
+    /// Frame of an animated GIF, WebP or PNG to tag, counting from 0
+    #[arg(long = "frame", default_value_t = 0, global = true)]
+    frame: usize,
+
+    /// Turn images upright according to their EXIF orientation before
+    /// tagging, as photo viewers do (JPEG, PNG and WebP)
+    #[arg(long = "respect-exif", global = true)]
+    respect_exif: bool,
+
+    /// Log more detail to stderr: -v for progress, -vv for debugging
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log errors, and skip the summary after tagging several images
+    #[arg(short = 'q', long = "quiet", conflicts_with = "verbose", global = true)]
+    quiet: bool,
+
+    /// Read default options from this TOML (`.toml`) or JSON file: `model`,
+    /// `wd14-model` (a list), `format`, and `general-threshold`,
+    /// `character-threshold`, `rating-threshold`, `relative-threshold`,
+    /// `general-mcut`, `character-mcut`, `character-mcut-floor` and `top-k`,
+    /// named like their flags; flags on the command line win
+    #[arg(long = "config", value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    model: ModelArgs,
 
-    /// Optional output file to write results
+    #[command(flatten)]
+    thresholds: ThresholdArgs,
+
+    /// Optional output file to write results to; with several images, a
+    /// directory or --input-list, it collects the results of all of them
     #[arg(short = 'o', long = "output")]
     output: Option<String>,
+
+    /// Write each image's results to its own file in this directory, e.g.
+    /// `image.png` → `DIR/image.txt`, creating it if needed; images found
+    /// in a directory input keep their subdirectory below it
+    #[arg(
+        long = "output-dir",
+        value_name = "DIR",
+        conflicts_with_all = ["output", "sidecar", "raw"]
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// Write each image's results to a sidecar file next to it, e.g.
+    /// `image.png` → `image.txt`, replacing any existing one
+    #[arg(long = "sidecar", conflicts_with_all = ["output", "raw"])]
+    sidecar: bool,
+
+    /// With --sidecar or --output-dir, leave images whose result file
+    /// already exists alone instead of tagging them again, so an interrupted
+    /// run can be resumed
+    #[arg(long = "skip-existing")]
+    skip_existing: bool,
+
+    /// Extension of sidecar files, from --sidecar or --output-dir
+    /// [default: `txt`, or `json` with --format json]
+    #[arg(long = "caption-ext", value_name = "EXT")]
+    caption_ext: Option<String>,
+
+    /// Escape parentheses in the text output (`fate \(series\)`), for
+    /// prompt tools that treat them as syntax; JSON keeps the tag names as
+    /// they are
+    #[arg(long = "escape-parens")]
+    escape_parens: bool,
+
+    /// Write general tags in the text output with weights from their
+    /// scores, as `(tag:0.87)` prompt syntax, to --score-precision decimal
+    /// places (default 2)
+    #[arg(long = "weighted")]
+    weighted: bool,
+
+    /// Follow each general and character tag in the text output with its
+    /// score, as `tag:0.87`, to --score-precision decimal places (default 2)
+    #[arg(long = "with-scores", conflicts_with = "weighted")]
+    with_scores: bool,
+
+    /// Multiplier from score to weight with --weighted, e.g. 1.5 turns a
+    /// 0.8 score into `(tag:1.20)`
+    #[arg(
+        long = "weight-scale",
+        default_value_t = 1.0,
+        value_parser = parse_positive,
+        requires = "weighted"
+    )]
+    weight_scale: f32,
+
+    /// When tagging several images, also write how often each general and
+    /// character tag was kept to this CSV file, as `tag,count,mean_score`
+    /// rows, most frequent first
+    #[arg(long = "report", value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Append to the --output file instead of overwriting it, one line per
+    /// image; JSON results are written as one compact object per line
+    #[arg(long = "append", requires = "output")]
+    append: bool,
+
+    /// Tag the image once and list the general tags kept at each of these
+    /// comma-separated thresholds, e.g. `0.3,0.4,0.5`, instead of the usual
+    /// output (single image only)
+    #[arg(
+        long = "threshold-sweep",
+        value_name = "THRESHOLDS",
+        value_delimiter = ',',
+        value_parser = parse_threshold,
+        conflicts_with = "raw"
+    )]
+    threshold_sweep: Vec<f32>,
+
+    /// Tag the image once and print how many general tags score in each
+    /// tenth of 0.0-1.0, as an ASCII histogram, instead of the usual output
+    /// (single image only); scores are rescaled by --temperature first, so
+    /// it helps pick a general threshold
+    #[arg(
+        long = "histogram",
+        conflicts_with_all = ["raw", "threshold_sweep"]
+    )]
+    histogram: bool,
+
+    /// Tag the image together with its horizontal mirror and its centre
+    /// and four corners cropped to 87.5% of each side, averaging the scores
+    /// of all seven before thresholding (single image only); steadier tags
+    /// for seven times the inference
+    #[arg(long = "tta")]
+    tta: bool,
+
+    /// Tag the image together with its horizontal mirror and average the
+    /// two sets of scores (single image only); a cheaper --tta
+    #[arg(long = "hflip-tta", conflicts_with = "tta")]
+    hflip_tta: bool,
+
+    /// Save the image the model is fed, after padding and resizing but
+    /// before conversion to floats, to this file (single image only); the
+    /// extension picks the format, e.g. `input.png`
+    #[arg(long = "dump-preprocessed", value_name = "FILE")]
+    dump_preprocessed: Option<PathBuf>,
+
+    /// Print only the comma-separated general tags, without the `Tags:`
+    /// prefix or the rating and character lines (and, for several images,
+    /// without the file names), for use in shell scripts; text format only
+    #[arg(long = "tags-only", conflicts_with = "raw")]
+    tags_only: bool,
+
+    /// Output format
+    #[arg(short = 'f', long = "format", default_value = "text")]
+    format: OutputFormat,
+
+    /// Worker threads used to decode and preprocess images with a directory
+    /// or --input-list
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    jobs: u16,
+
+    /// Images per model call with a directory or --input-list; larger
+    /// batches are faster, especially on a GPU, but use more memory
+    #[arg(
+        short = 'b',
+        long = "batch-size",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    batch_size: u16,
+
+    /// Don't show a progress bar for a directory or --input-list (it is also
+    /// hidden when stderr isn't a terminal)
+    #[arg(long = "no-progress")]
+    no_progress: bool,
+
+    /// Keep preprocessed images in this directory, keyed by file contents and
+    /// preprocessing settings, so tagging the same images again skips
+    /// decoding and resizing; entries take about 2.4 MB each for WD14
+    #[arg(long = "tensor-cache", value_name = "DIR")]
+    tensor_cache: Option<PathBuf>,
+
+    /// Write every tag's unthresholded score as `tag,category,score` CSV
+    /// instead of the usual output (single image only)
+    #[arg(long = "raw", conflicts_with = "format")]
+    raw: bool,
 }
 
-fn main() {
-    ort::init()
-        .with_logger(Arc::new(
-            |_level: ort::logging::LogLevel,
-             _category: &str,
-             _id: &str,
-             _code_location: &str,
-             _message: &str| {
-                //println!("ORT: {}", message);
+impl Args {
+    /// Whether each image's results go to a file of its own.
+    fn per_image(&self) -> bool {
+        self.sidecar || self.output_dir.is_some()
+    }
+
+    /// The comma-separated general tags of `prediction` for text output.
+    fn caption(&self, prediction: &Prediction) -> String {
+        if !self.weighted && !self.with_scores {
+            return self.text_tags(&prediction.general_string);
+        }
+        let mut tags: Vec<String> = prediction
+            .general
+            .iter()
+            .map(|(tag, score)| {
+                if self.weighted {
+                    format!(
+                        "({}:{:.*})",
+                        self.text_tags(tag),
+                        self.text_precision(),
+                        score * self.weight_scale
+                    )
+                } else {
+                    self.scored_tag(tag, *score)
+                }
+            })
+            .collect();
+        // `general_string` would have the rating first; it is never weighted.
+        if let (true, Some((rating, score))) =
+            (self.thresholds.include_rating_tag, &prediction.rating)
+        {
+            tags.insert(0, self.scored_tag(rating, *score));
+        }
+        tags.join(", ")
+    }
+
+    /// The comma-separated character tags of `prediction` for text output.
+    fn character_caption(&self, prediction: &Prediction) -> String {
+        let tags: Vec<String> = prediction
+            .characters
+            .iter()
+            .map(|(tag, score)| self.scored_tag(tag, *score))
+            .collect();
+        tags.join(", ")
+    }
+
+    /// `tag`, followed by `:score` with --with-scores.
+    fn scored_tag(&self, tag: &str, score: f32) -> String {
+        if self.with_scores {
+            format!(
+                "{}:{:.*}",
+                self.text_tags(tag),
+                self.text_precision(),
+                score
+            )
+        } else {
+            self.text_tags(tag)
+        }
+    }
+
+    /// Decimal places of the scores and weights in text output:
+    /// --score-precision, or 2.
+    fn text_precision(&self) -> usize {
+        self.thresholds.score_precision.unwrap_or(2) as usize
+    }
+
+    fn text_tags(&self, tags: &str) -> String {
+        if self.escape_parens {
+            escape_parens(tags)
+        } else {
+            tags.to_string()
+        }
+    }
+
+    /// The views of `img` to average with --tta or --hflip-tta, or `None` to
+    /// tag it as is.
+    fn tta_views(&self, img: &DynamicImage) -> Option<Vec<DynamicImage>> {
+        if self.tta {
+            Some(tta_views(img))
+        } else if self.hflip_tta {
+            Some(hflip_views(img))
+        } else {
+            None
+        }
+    }
+
+    /// Tags `img`, averaging over its --tta or --hflip-tta views if asked
+    /// to.
+    fn predict(&self, pred: &mut Predictor, img: &DynamicImage) -> wd14_tagger::Result<Prediction> {
+        let thresholds = &self.thresholds;
+        match self.tta_views(img) {
+            Some(views) => pred
+                .predict_averaged(&views, &thresholds.config(), &thresholds.filter)
+                .map(|prediction| thresholds.finish(prediction)),
+            None => thresholds.predict(pred, img),
+        }
+    }
+
+    /// Scores `img` like `predict`, without thresholds.
+    fn predict_raw(
+        &self,
+        pred: &mut Predictor,
+        img: &DynamicImage,
+    ) -> wd14_tagger::Result<RawPrediction> {
+        match self.tta_views(img) {
+            Some(views) => pred.predict_raw_averaged(&views),
+            None => pred.predict_raw(img),
+        }
+    }
+
+    fn decode_options(&self) -> DecodeOptions {
+        DecodeOptions {
+            frame: self.frame,
+            respect_exif: self.respect_exif,
+        }
+    }
+
+    fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Warn,
+            (false, 1) => LevelFilter::Info,
+            (false, 2) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve `POST /tag` over HTTP, keeping the model loaded between requests
+    Serve {
+        /// Address to listen on
+        #[arg(long = "bind", default_value = "127.0.0.1:8080")]
+        bind: String,
+    },
+    /// Tag one image repeatedly and report preprocessing and inference times
+    Bench {
+        /// Image to tag
+        image: PathBuf,
+
+        /// Timed runs, after one untimed warm-up
+        #[arg(
+            short = 'n',
+            long = "iterations",
+            default_value_t = 20,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        iterations: u32,
+    },
+    /// Tag two images and show which general tags only one of them has, and
+    /// how the scores of the shared ones differ
+    Diff {
+        /// First image
+        a: PathBuf,
+        /// Second image
+        b: PathBuf,
+    },
+    /// List the known WD14 taggers for `--wd14-model` and the other
+    /// `--model` backends, with their input sizes
+    ListModels,
+}
+
+/// Which model to load and where to run it.
+#[derive(clap::Args, Debug)]
+struct ModelArgs {
+    /// Model backend to use
+    #[arg(short = 'm', long = "model", default_value = "wd14", global = true)]
+    model: ModelKind,
+
+    /// WD14 tagger to use with `--model wd14`; repeat to ensemble several
+    /// taggers sharing a tag list by averaging their scores
+    #[arg(long = "wd14-model", default_value = WD14_DEFAULT_MODEL, global = true)]
+    wd14_model: Vec<String>,
+
+    /// Branch, tag or commit of the model's hub repo to download from, to
+    /// pin an exact model version [default: `main`]
+    #[arg(long = "revision", value_name = "REV", global = true)]
+    revision: Option<String>,
+
+    /// Execution provider to run the model on; falls back to the CPU when
+    /// unavailable
+    #[arg(long = "device", default_value = "cuda", global = true)]
+    device: Device,
+
+    /// GPU to use with any --device other than `cpu`
+    #[arg(long = "device-id", default_value_t = 0, global = true)]
+    device_id: i32,
+
+    /// ONNX Runtime graph optimisation level [default: ONNX Runtime's,
+    /// `all`]; try a lower one if an int8-quantized model is slow or fails
+    /// to load
+    #[arg(long = "optimization-level", value_name = "LEVEL", global = true)]
+    optimization_level: Option<OptimizationLevel>,
+
+    /// Run one inference on a blank image right after loading the model, so
+    /// the first real one isn't slower than the rest (always on for `serve`)
+    #[arg(long = "warmup", global = true)]
+    warmup: bool,
+
+    /// Load the model from this ONNX file instead of the Hugging Face hub;
+    /// requires --tags-path
+    #[arg(
+        long = "model-path",
+        value_name = "FILE",
+        requires = "tags_path",
+        global = true
+    )]
+    model_path: Option<PathBuf>,
+
+    /// Tag list for --model-path: the tag CSV for WD14, or the vocabulary
+    /// JSON for DINOv3
+    #[arg(
+        long = "tags-path",
+        value_name = "FILE",
+        requires = "model_path",
+        global = true
+    )]
+    tags_path: Option<PathBuf>,
+
+    /// Name of the model input to feed images to, for exports whose image
+    /// input isn't the first (WD14 only)
+    #[arg(long = "input-name", value_name = "NAME", global = true)]
+    input_name: Option<String>,
+
+    /// Name of the model output holding the tag scores, for models with
+    /// several heads (WD14 only)
+    #[arg(long = "output-name", value_name = "NAME", global = true)]
+    output_name: Option<String>,
+
+    /// Position of the model output holding the tag scores, counting from 0
+    /// (WD14 only)
+    #[arg(
+        long = "output-index",
+        value_name = "N",
+        conflicts_with = "output_name",
+        global = true
+    )]
+    output_index: Option<usize>,
+
+    /// Square input size in pixels for models whose input size is dynamic
+    /// (WD14 only); a model with a fixed size fails to load unless it matches
+    #[arg(
+        long = "size",
+        value_name = "PIXELS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        global = true
+    )]
+    size: Option<u32>,
+
+    /// Only use models already in the local Hugging Face cache
+    #[arg(long = "offline", global = true)]
+    offline: bool,
+
+    /// How many times to retry a model or tag list download that failed with
+    /// a network error, a 429 or a 5xx response, waiting 1s, 2s, 4s, ... in
+    /// between; other errors such as a 404 fail at once
+    #[arg(long = "max-retries", default_value_t = 2, global = true)]
+    max_retries: u32,
+
+    /// Colour of the square padding around non-square images, and behind
+    /// transparent pixels unless --background is given: `black`, `white` or
+    /// an RGB hex code such as `#7f7f7f` (WD14 only)
+    #[arg(long = "pad-color", default_value = "black", value_parser = parse_color, global = true)]
+    pad_color: [u8; 3],
+
+    /// Colour to composite transparent pixels onto before tagging, in the
+    /// same forms as --pad-color, so transparent PNGs such as stickers and
+    /// icons tag the same whatever their hidden pixels hold [default: the
+    /// --pad-color for WD14; DINOv3 ignores transparency]
+    #[arg(long = "background", value_name = "COLOR", value_parser = parse_color, global = true)]
+    background: Option<[u8; 3]>,
+
+    /// Resampling filter for scaling images to the model's input size
+    /// (WD14 only)
+    #[arg(long = "resize-filter", default_value = "catmull-rom", global = true)]
+    resize_filter: ResizeFilter,
+
+    /// Make non-square images square by padding, which keeps the aspect ratio
+    /// as in training, stretching, which distorts it and usually costs
+    /// accuracy, or cropping to the centred square, which leaves the edges of
+    /// wide or tall images untagged (WD14 only)
+    #[arg(long = "fit", default_value = "pad", global = true)]
+    fit: Fit,
+
+    /// Tag the largest centred square of the image instead of padding it,
+    /// so padding borders can't cause tags like `border`; shorthand for
+    /// `--fit crop`
+    #[arg(long = "crop-to-content", global = true, conflicts_with = "fit")]
+    crop_to_content: bool,
+
+    /// Channel order the model expects; defaults to the model's
+    /// `channel_order` metadata, else `bgr` like the reference exports
+    /// (WD14 only)
+    #[arg(long = "channel-order", global = true)]
+    channel_order: Option<ChannelOrder>,
+
+    /// Resample 16-bit and float images at their full precision instead of
+    /// reducing them to 8 bits first (WD14 only)
+    #[arg(long = "keep-bit-depth", global = true)]
+    keep_bit_depth: bool,
+
+    /// Scaling of the pixel values fed to the model: `none` keeps 0-255 like
+    /// the reference exports, `zero_one` divides by 255, `imagenet` also
+    /// applies the ImageNet mean and standard deviation. A wrong choice
+    /// silently ruins the tags (WD14 only)
+    #[arg(long = "normalize", default_value = "none", global = true)]
+    normalize: Normalize,
+
+    /// How to write tag names: `spaces` turns underscores into spaces
+    /// except in emoticons like `^_^`, `keep` leaves them as in the label
+    /// file, `escape-parens` also escapes parentheses
+    #[arg(long = "tag-style", default_value = "spaces", global = true)]
+    tag_style: TagStyle,
+
+    /// Keep the underscores in tag names (`long_hair`) instead of replacing
+    /// them with spaces; shorthand for `--tag-style keep`
+    #[arg(long = "underscores", global = true, conflicts_with = "tag_style")]
+    underscores: bool,
+
+    /// CSV file of `code,category` rows, e.g. `3,general`, putting tags of a
+    /// numeric label-file category in the `rating`, `general` or `character`
+    /// bucket, or `ignore`-ing them; unlisted codes keep the WD14 mapping
+    /// (9 rating, 0 general, 4 character)
+    #[arg(long = "category-map", value_name = "FILE", global = true)]
+    category_map: Option<PathBuf>,
+}
+
+impl ModelArgs {
+    fn predictor(&self) -> Predictor {
+        let pred = Predictor::new(self.model)
+            .with_wd14_models(&self.wd14_model)
+            .with_offline(self.offline)
+            .with_max_retries(self.max_retries)
+            .with_warmup(self.warmup)
+            .with_device(self.device, self.device_id)
+            .with_preprocess_options(PreprocessOptions {
+                pad_color: self.pad_color,
+                background: self.background,
+                resize_filter: self.resize_filter,
+                fit: if self.crop_to_content {
+                    Fit::Crop
+                } else {
+                    self.fit
+                },
+                channel_order: self.channel_order,
+                keep_bit_depth: self.keep_bit_depth,
+                normalize: self.normalize,
+            })
+            .with_tag_style(self.tag_style)
+            .with_underscores(self.underscores);
+        let pred = match &self.revision {
+            Some(revision) => pred.with_revision(revision),
+            None => pred,
+        };
+        let pred = match &self.input_name {
+            Some(name) => pred.with_input_name(name),
+            None => pred,
+        };
+        let pred = match self.optimization_level {
+            Some(level) => pred.with_optimization_level(level),
+            None => pred,
+        };
+        let pred = match self.size {
+            Some(size) => pred.with_input_size(size as usize),
+            None => pred,
+        };
+        let pred = match (&self.output_name, self.output_index) {
+            (Some(name), _) => pred.with_output(OutputSelector::Name(name.clone())),
+            (None, Some(index)) => pred.with_output(OutputSelector::Index(index)),
+            (None, None) => pred,
+        };
+        match (&self.model_path, &self.tags_path) {
+            (Some(model), Some(tags)) => pred.with_local_files(model, tags),
+            _ => pred,
+        }
+    }
+}
+
+/// Which tags to keep. The server accepts the same names as query parameters.
+#[derive(clap::Args, Debug, Clone)]
+struct ThresholdArgs {
+    /// Minimum score for a general tag to be kept (ignored with --general-mcut)
+    #[arg(
+        long = "general-threshold",
+        default_value_t = 0.35,
+        value_parser = parse_threshold,
+        global = true
+    )]
+    general_threshold: f32,
+
+    /// Minimum score for a character tag to be kept (ignored with --character-mcut)
+    #[arg(
+        long = "character-threshold",
+        default_value_t = 0.85,
+        value_parser = parse_threshold,
+        global = true
+    )]
+    character_threshold: f32,
+
+    /// Pick the general threshold per image with MCut instead of
+    /// --general-threshold
+    #[arg(long = "general-mcut", global = true)]
+    general_mcut: bool,
+
+    /// Pick the character threshold per image with MCut instead of
+    /// --character-threshold
+    #[arg(long = "character-mcut", global = true)]
+    character_mcut: bool,
+
+    /// Lowest threshold --character-mcut may pick
+    #[arg(
+        long = "character-mcut-floor",
+        default_value_t = 0.15,
+        value_parser = parse_threshold,
+        global = true
+    )]
+    character_mcut_floor: f32,
+
+    /// Keep exactly the N highest-scoring general tags, ignoring
+    /// --general-threshold, --general-mcut and --relative-threshold
+    #[arg(long = "top-k", value_name = "N", global = true)]
+    top_k: Option<usize>,
+
+    /// Keep the general tags scoring at least R times the top general score,
+    /// ignoring --general-threshold and --general-mcut (but not --top-k)
+    #[arg(
+        long = "relative-threshold",
+        value_name = "R",
+        value_parser = parse_threshold,
+        global = true
+    )]
+    relative_threshold: Option<f32>,
+
+    /// Report no general tags, e.g. when only the rating is wanted
+    #[arg(long = "no-general", global = true)]
+    no_general: bool,
+
+    /// Report no character tags, e.g. for plain captions
+    #[arg(long = "no-characters", global = true)]
+    no_characters: bool,
+
+    /// Minimum score for the top rating to be reported; below it the
+    /// rating is left out (same as `--threshold-per-category rating=SCORE`)
+    #[arg(
+        long = "rating-threshold",
+        value_name = "SCORE",
+        value_parser = parse_threshold,
+        global = true
+    )]
+    rating_threshold: Option<f32>,
+
+    /// Per-category selection overriding the options above, as comma-separated
+    /// CATEGORY=SETTING pairs: CATEGORY is `rating`, `general` or
+    /// `character`, SETTING a minimum score, `mcut`, `top:N`, `rel:R` (see
+    /// --relative-threshold) or `off`, e.g. `rating=0.5,character=mcut`
+    #[arg(
+        long = "threshold-per-category",
+        value_name = "CATEGORY=SETTING",
+        value_delimiter = ',',
+        value_parser = parse_category_threshold,
+        global = true
+    )]
+    threshold_per_category: Vec<CategoryThreshold>,
+
+    /// Keep at most N general tags after thresholding, the highest scoring
+    #[arg(long = "max-general", value_name = "N", global = true)]
+    max_general: Option<usize>,
+
+    /// Keep at most N character tags after thresholding, the highest scoring
+    #[arg(long = "max-character", value_name = "N", global = true)]
+    max_character: Option<usize>,
+
+    /// Keep at least N general tags: if thresholding leaves fewer, take the
+    /// N highest scoring instead, so no image gets an empty caption
+    #[arg(long = "min-tags", value_name = "N", global = true)]
+    min_tags: Option<usize>,
+
+    /// Order of the general tags in the output
+    #[arg(long = "sort", value_enum, default_value_t = TagOrder::Score, global = true)]
+    sort: TagOrder,
+
+    /// Rescale every score with temperature T, as sigmoid(logit(score) / T),
+    /// before the thresholds above are applied; above 1 flattens the scores,
+    /// below 1 sharpens them
+    #[arg(
+        long = "temperature",
+        value_name = "T",
+        default_value_t = 1.0,
+        value_parser = parse_positive,
+        global = true
+    )]
+    temperature: f32,
+
+    /// Rescale the rating scores to sum to 1, before --rating-threshold
+    /// sees them
+    #[arg(long = "softmax-ratings", global = true)]
+    softmax_ratings: bool,
+
+    /// Start the tag list with the top rating tag, e.g. `general, 1girl, ...`
+    #[arg(long = "include-rating-tag", global = true)]
+    include_rating_tag: bool,
+
+    /// Round the scores in the output to N decimal places, at most 9
+    #[arg(
+        long = "score-precision",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(..=MAX_SCORE_PRECISION as i64),
+        global = true
+    )]
+    score_precision: Option<u32>,
+
+    /// File of tags, one per line, never to report; wins over --include
+    #[arg(long = "exclude", value_name = "FILE", global = true)]
+    exclude: Option<PathBuf>,
+
+    /// File of tags, one per line, to report exclusively
+    #[arg(long = "include", value_name = "FILE", global = true)]
+    include: Option<PathBuf>,
+
+    /// CSV file of `tag,implied` rows, e.g. `blue_eyes,eyes`; a general tag
+    /// implied by another kept one is left out as redundant
+    #[arg(long = "implications", value_name = "FILE", global = true)]
+    implications: Option<PathBuf>,
+
+    /// Tag lists read from --exclude, --include and --implications, shared
+    /// so that copies of these options (one per server request) are cheap
+    #[arg(skip)]
+    filter: Arc<TagFilter>,
+}
+
+impl ThresholdArgs {
+    /// Reads the --exclude, --include and --implications files into
+    /// `self.filter`.
+    fn load_filter(&mut self) -> wd14_tagger::Result<()> {
+        let mut filter = TagFilter::default();
+        if let Some(path) = &self.exclude {
+            filter.exclude = load_tag_list(path)?;
+        }
+        if let Some(path) = &self.include {
+            filter.include = Some(load_tag_list(path)?);
+        }
+        if let Some(path) = &self.implications {
+            filter.implications = load_implications(path)?;
+        }
+        self.filter = Arc::new(filter);
+        Ok(())
+    }
+
+    /// The selection given by the individual options, then overridden by
+    /// --threshold-per-category.
+    fn config(&self) -> ThresholdConfig {
+        let mut config = ThresholdConfig {
+            rating: match self.rating_threshold {
+                Some(min) => Threshold::Fixed(min),
+                None => ThresholdConfig::default().rating,
+            },
+            general: match (self.top_k, self.relative_threshold, self.general_mcut) {
+                _ if self.no_general => Threshold::Off,
+                (Some(k), _, _) => Threshold::TopK(k),
+                (None, Some(ratio), _) => Threshold::Relative(ratio),
+                (None, None, true) => Threshold::Mcut { floor: 0.0 },
+                (None, None, false) => Threshold::Fixed(self.general_threshold),
+            },
+            character: if self.no_characters {
+                Threshold::Off
+            } else if self.character_mcut {
+                Threshold::Mcut {
+                    floor: self.character_mcut_floor,
+                }
+            } else {
+                Threshold::Fixed(self.character_threshold)
             },
-        ))
-        .commit();
+            general_order: self.sort,
+            max_general: self.max_general,
+            max_character: self.max_character,
+            min_general: self.min_tags,
+            temperature: self.temperature,
+            softmax_ratings: self.softmax_ratings,
+        };
+        for &CategoryThreshold { category, setting } in &self.threshold_per_category {
+            let floor = match category {
+                Category::Character => self.character_mcut_floor,
+                Category::Rating | Category::General => 0.0,
+            };
+            let threshold = match setting {
+                Setting::Fixed(min) => Threshold::Fixed(min),
+                Setting::Mcut => Threshold::Mcut { floor },
+                Setting::TopK(k) => Threshold::TopK(k),
+                Setting::Relative(ratio) => Threshold::Relative(ratio),
+                Setting::Off => Threshold::Off,
+            };
+            match category {
+                Category::Rating => config.rating = threshold,
+                Category::General => config.general = threshold,
+                Category::Character => config.character = threshold,
+            }
+        }
+        config
+    }
 
-    let args = Args::parse();
+    fn predict(&self, pred: &mut Predictor, img: &DynamicImage) -> wd14_tagger::Result<Prediction> {
+        pred.predict(img, &self.config(), &self.filter)
+            .map(|prediction| self.finish(prediction))
+    }
 
-    let mut pred = Predictor::new(args.model);
-    let img = match image::open(&args.image) {
-        Ok(img) => img,
+    fn predict_batch_prepared(
+        &self,
+        pred: &mut Predictor,
+        inputs: Vec<Array4<f32>>,
+    ) -> wd14_tagger::Result<Vec<Prediction>> {
+        pred.predict_batch_prepared(inputs, &self.config(), &self.filter)
+            .map(|predictions| predictions.into_iter().map(|p| self.finish(p)).collect())
+    }
+
+    /// Applies the options that post-process a finished prediction.
+    fn finish(&self, mut prediction: Prediction) -> Prediction {
+        if self.include_rating_tag {
+            prediction.prepend_rating();
+        }
+        if let Some(decimals) = self.score_precision {
+            prediction.round_scores(decimals);
+        }
+        prediction
+    }
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    match s {
+        "black" => return Ok([0, 0, 0]),
+        "white" => return Ok([255, 255, 255]),
+        _ => {}
+    }
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!(
+            "'{}' is not `black`, `white` or an RGB hex code",
+            s
+        )),
+    }
+}
+
+/// One `--threshold-per-category` entry.
+#[derive(Debug, Clone, Copy)]
+struct CategoryThreshold {
+    category: Category,
+    setting: Setting,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Category {
+    Rating,
+    General,
+    Character,
+}
+
+/// A [`Threshold`] before the MCut floor is known.
+#[derive(Debug, Clone, Copy)]
+enum Setting {
+    Fixed(f32),
+    Mcut,
+    TopK(usize),
+    Relative(f32),
+    Off,
+}
+
+fn parse_category_threshold(s: &str) -> Result<CategoryThreshold, String> {
+    let (category, setting) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not CATEGORY=SETTING", s))?;
+    let category = match category {
+        "rating" => Category::Rating,
+        "general" => Category::General,
+        "character" => Category::Character,
+        _ => {
+            return Err(format!(
+                "unknown category '{}', expected `rating`, `general` or `character`",
+                category
+            ))
+        }
+    };
+    let setting = if setting == "mcut" {
+        Setting::Mcut
+    } else if setting == "off" {
+        Setting::Off
+    } else if let Some(k) = setting.strip_prefix("top:") {
+        Setting::TopK(k.parse().map_err(|_| format!("'{}' is not a count", k))?)
+    } else if let Some(ratio) = setting.strip_prefix("rel:") {
+        Setting::Relative(parse_threshold(ratio)?)
+    } else {
+        Setting::Fixed(parse_threshold(setting)?)
+    };
+    Ok(CategoryThreshold { category, setting })
+}
+
+fn parse_threshold(s: &str) -> Result<f32, String> {
+    let value: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    check_threshold(value)
+}
+
+fn check_threshold(value: f32) -> Result<f32, String> {
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{} is not within 0.0..=1.0", value))
+    }
+}
+
+fn parse_positive(s: &str) -> Result<f32, String> {
+    let value: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if value > 0.0 && value.is_finite() {
+        Ok(value)
+    } else {
+        Err(format!("{} is not a positive number", value))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// `Tags:`/`Rating:`/`Characters:` lines, or bare tags when writing a file
+    Text,
+    /// Tags with their confidence scores as JSON
+    Json,
+    /// JSON as one compact object per line, each written as soon as its
+    /// image is tagged
+    Ndjson,
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(args.log_level());
+    if let Some(path) = args.config.clone() {
+        if let Err(e) = Config::load(&path).and_then(|config| config.apply(&mut args, &matches)) {
+            eprintln!("Failed to read config '{}': {}", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Err(e) = args.thresholds.load_filter() {
+        eprintln!("Failed to read tag list: {}", e);
+        exit(1);
+    }
+
+    if let Some(Command::ListModels) = args.command {
+        list_models();
+        return;
+    }
+    if args.model.model == ModelKind::Wd14 && args.model.model_path.is_none() {
+        for name in &args.model.wd14_model {
+            if !WD14_KNOWN_MODELS.iter().any(|(known, _)| known == name) {
+                warn!(
+                    "'{}' is not a known WD14 tagger, so downloading it may fail; \
+                     see `wd14-tagger list-models`",
+                    name
+                );
+            }
+        }
+    }
+
+    let mut pred = args.model.predictor();
+    if let Some(path) = &args.model.category_map {
+        match load_category_map(path) {
+            Ok(categories) => pred = pred.with_category_map(categories),
+            Err(e) => {
+                eprintln!("Failed to read category map: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    match &args.command {
+        Some(Command::Serve { bind }) => {
+            // Load and warm up before listening, so no request waits for it.
+            let mut pred = pred.with_warmup(true);
+            load_or_exit(&mut pred);
+            if let Err(e) = server::serve(pred, bind, &args.thresholds, args.decode_options()) {
+                eprintln!("Server error on {}: {}", bind, e);
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Bench { image, iterations }) => {
+            let img = match read_image(image, args.decode_options()) {
+                Ok(img) => img,
+                Err(e) => {
+                    eprintln!("Failed to open image '{}': {}", image.display(), e);
+                    exit(1);
+                }
+            };
+            load_or_exit(&mut pred);
+            if let Err(e) = bench::run(&mut pred, &img, *iterations as usize, &args.thresholds) {
+                eprintln!("Benchmark failed: {}", e);
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Diff { a, b }) => {
+            let [prediction_a, prediction_b] = [a, b].map(|path| {
+                let img = read_image(path, args.decode_options()).unwrap_or_else(|e| {
+                    eprintln!("Failed to open image '{}': {}", path.display(), e);
+                    exit(1);
+                });
+                load_or_exit(&mut pred);
+                args.thresholds
+                    .predict(&mut pred, &img)
+                    .unwrap_or_else(|e| {
+                        eprintln!("Failed to tag '{}': {}", path.display(), e);
+                        exit(1);
+                    })
+            });
+            diff::print(a, &prediction_a, b, &prediction_b, args.text_precision());
+            return;
+        }
+        Some(Command::ListModels) => unreachable!("handled before loading"),
+        None => {}
+    }
+
+    if args.dry_run {
+        load_or_exit(&mut pred);
+        print_model_summary(&pred, &args.model);
+        return;
+    }
+
+    if args.skip_existing && !args.per_image() {
+        eprintln!("--skip-existing needs --sidecar or --output-dir to find earlier results");
+        exit(1);
+    }
+    if let Some(output) = args.output.as_deref().map(Path::new) {
+        if output.is_dir() {
+            eprintln!(
+                "--output '{}' is a directory; use --output-dir for one file per image",
+                output.display()
+            );
+            exit(1);
+        }
+    }
+    if let Some(dir) = &args.output_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Failed to create '{}': {}", dir.display(), e);
+            exit(1);
+        }
+    }
+
+    if let Some(list) = &args.input_list {
+        let images = match read_input_list(list) {
+            Ok(images) => images,
+            Err(e) => {
+                eprintln!("Failed to read input list '{}': {}", list.display(), e);
+                exit(1);
+            }
+        };
+        tag_images(&mut pred, &images, &args);
+        return;
+    }
+
+    if args.image.len() > 1 {
+        if args.raw {
+            eprintln!(
+                "--raw only supports a single image, not {}",
+                args.image.len()
+            );
+            exit(1);
+        }
+        let mut images = vec![];
+        for image in &args.image {
+            let path = Path::new(image);
+            if path.is_dir() {
+                let mut found = vec![];
+                collect_images(path, &args.ext, &mut found);
+                found.sort();
+                images.extend(found);
+            } else if args.per_image() && (path == Path::new("-") || is_url(path)) {
+                eprintln!(
+                    "--sidecar and --output-dir need image files to name results after, not '{}'",
+                    image
+                );
+                exit(1);
+            } else {
+                images.push(path.to_path_buf());
+            }
+        }
+        tag_images(&mut pred, &images, &args);
+        return;
+    }
+
+    let input = Path::new(
+        args.image
+            .first()
+            .expect("required without a subcommand or --input-list"),
+    );
+    if input.is_dir() {
+        if args.raw {
+            eprintln!("--raw only supports a single image, not a directory");
+            exit(1);
+        }
+        tag_directory(&mut pred, input, &args);
+    } else {
+        tag_file(&mut pred, input, &args);
+    }
+}
+
+/// Prints `list-models`.
+fn list_models() {
+    println!(
+        "WD14 taggers (--model wd14 --wd14-model NAME), from {}:",
+        WD14_REPO
+    );
+    for (name, size) in WD14_KNOWN_MODELS {
+        let default = if *name == WD14_DEFAULT_MODEL {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("  {:<42} {}x{}{}", name, size, size, default);
+    }
+    println!();
+    println!("Other backends:");
+    println!("  {:<42} the image's size, up to 1024", "--model dino");
+    println!("  {:<42} the same, int8-quantized", "--model dino_q8");
+}
+
+/// Prints what `--dry-run` loaded.
+fn print_model_summary(pred: &Predictor, model: &ModelArgs) {
+    let source = match (&model.model_path, model.model) {
+        (Some(path), _) => path.display().to_string(),
+        (None, ModelKind::Wd14) => model.wd14_model.join(", "),
+        (None, kind) => format!("{:?}", kind),
+    };
+    println!("Model: {}", source);
+    let info = match pred.model_info() {
+        Ok(info) => info,
         Err(e) => {
-            eprintln!("Failed to open image '{}': {}", &args.image, e);
+            eprintln!("Failed to inspect model: {}", e);
             exit(1);
         }
     };
-    let output = pred.predict(&img, 0.35, false, 0.85, false, &[]);
+    println!("Sessions: {}", info.sessions);
+    for (name, shape) in &info.inputs {
+        println!("Input: {} {:?}", name, shape);
+    }
+    for (name, shape) in &info.outputs {
+        println!("Output: {} {:?}", name, shape);
+    }
+    if let Some(size) = info.input_size {
+        println!("Input size: {}x{}", size, size);
+    }
+    if info.greyscale {
+        println!("Channels: 1 (greyscale)");
+    } else if let Some(order) = info.channel_order {
+        println!("Channel order: {:?}", order);
+    }
+    let labels = pred.labels();
+    println!(
+        "Tags: {} ({} rating, {} general, {} character)",
+        labels.names.len(),
+        labels.rating.len(),
+        labels.general.len(),
+        labels.character.len()
+    );
+}
 
-    let g_str: Vec<String> = output.general.iter().map(|g| g.0.to_string()).collect();
-    let c_str: Vec<String> = output.characters.iter().map(|c| c.0.to_string()).collect();
+fn load_or_exit(pred: &mut Predictor) {
+    match pred.load() {
+        Ok(()) => {}
+        Err(TaggerError::DynamicInputSize { shape }) => {
+            eprintln!(
+                "Failed to load model: its input {:?} has a dynamic image size; \
+                 pass --size, e.g. --size 448",
+                shape
+            );
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to load model: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn write_or_exit(filename: &Path, contents: &str) {
+    if let Err(e) = File::create(filename).and_then(|mut f| f.write_all(contents.as_bytes())) {
+        eprintln!("Failed to write to {}: {}", filename.display(), e);
+        exit(1);
+    }
+}
+
+/// Opens `--output` for writing, appending with `--append`.
+fn open_output(args: &Args, filename: &Path) -> File {
+    let file = if args.append {
+        OpenOptions::new().create(true).append(true).open(filename)
+    } else {
+        File::create(filename)
+    };
+    file.unwrap_or_else(|e| {
+        eprintln!("Failed to open {}: {}", filename.display(), e);
+        exit(1);
+    })
+}
+
+/// Writes the results to `--output`, appending with `--append`.
+fn write_output(args: &Args, filename: &Path, contents: &str) {
+    if let Err(e) = open_output(args, filename).write_all(contents.as_bytes()) {
+        eprintln!("Failed to write to {}: {}", filename.display(), e);
+        exit(1);
+    }
+}
+
+/// How long downloading an image URL may take, connecting included.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// Downloads the image at `url`, refusing responses that declare a
+/// non-image content type (such as an HTML error page).
+fn download_image(url: &str) -> Result<Vec<u8>, String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("download failed: {}", e))?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    if !(content_type.is_empty()
+        || content_type.starts_with("image/")
+        || content_type.starts_with("application/octet-stream"))
+    {
+        return Err(format!("server sent '{}', not an image", content_type));
+    }
+    let bytes = response
+        .bytes()
+        .map_err(|e| format!("download failed: {}", e))?;
+    Ok(bytes.to_vec())
+}
+
+/// Reads an image from `path`, from the web when `path` is a URL, or from
+/// stdin when `path` is `-`.
+fn read_image(path: &Path, options: DecodeOptions) -> Result<DynamicImage, String> {
+    if is_url(path) {
+        let bytes = download_image(&path.to_string_lossy())?;
+        return decode_image(&bytes, options);
+    }
+    if path != Path::new("-") {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        return decode_image(&bytes, options);
+    }
+    let mut bytes = vec![];
+    io::stdin()
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("failed to read stdin: {}", e))?;
+    if bytes.is_empty() {
+        return Err("stdin is empty".to_string());
+    }
+    decode_image(&bytes, options).map_err(|e| format!("stdin is not a valid image: {}", e))
+}
 
-    match args.output {
-        Some(filename) => {
-            if let Err(e) =
-                File::create(&filename).and_then(|mut f| f.write_all(g_str.join(", ").as_bytes()))
-            {
-                eprintln!("Failed to write to {}: {}", filename, e);
+/// Saves the model's view of `img` for --dump-preprocessed.
+fn dump_preprocessed(pred: &Predictor, img: &DynamicImage, path: &Path) {
+    let preview = pred
+        .preprocessor()
+        .and_then(|preprocessor| preprocessor.preview(img));
+    let result = match preview {
+        Ok(preview) => preview.save(path).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to write '{}': {}", path.display(), e);
+        exit(1);
+    }
+}
+
+fn tag_file(pred: &mut Predictor, path: &Path, args: &Args) {
+    if args.per_image() && (path == Path::new("-") || is_url(path)) {
+        eprintln!("--sidecar and --output-dir need an image file to name the result after");
+        exit(1);
+    }
+    if args.skip_existing {
+        let dir = match &args.output_dir {
+            Some(dir) => dir.as_path(),
+            None => path.parent().unwrap_or(Path::new("")),
+        };
+        let result = sidecar_path(args, dir, path);
+        if result.exists() {
+            info!(
+                "skipping '{}': '{}' exists",
+                path.display(),
+                result.display()
+            );
+            return;
+        }
+    }
+    let img = match read_image(path, args.decode_options()) {
+        Ok(img) => img,
+        Err(e) => {
+            eprintln!("Failed to open image '{}': {}", path.display(), e);
+            exit(1);
+        }
+    };
+    load_or_exit(pred);
+    if let Some(dump) = &args.dump_preprocessed {
+        dump_preprocessed(pred, &img, dump);
+    }
+    if !args.threshold_sweep.is_empty() {
+        match args.predict_raw(pred, &img) {
+            Ok(raw) => sweep::print(&raw, &args.threshold_sweep, &args.thresholds, args.format),
+            Err(e) => {
+                eprintln!("Failed to tag '{}': {}", path.display(), e);
                 exit(1);
             }
         }
-        None => {
-            println!("Tags: {}", g_str.join(", "));
-            println!("Rating: {:?}", output.rating);
-            println!("Characters: {:?}", c_str.join(", "));
+        return;
+    }
+    if args.histogram {
+        match args.predict_raw(pred, &img) {
+            Ok(raw) => histogram::print(&raw, &args.thresholds.config()),
+            Err(e) => {
+                eprintln!("Failed to tag '{}': {}", path.display(), e);
+                exit(1);
+            }
+        }
+        return;
+    }
+    if args.raw {
+        let mut raw = match args.predict_raw(pred, &img) {
+            Ok(raw) => raw,
+            Err(e) => {
+                eprintln!("Failed to tag '{}': {}", path.display(), e);
+                exit(1);
+            }
+        };
+        if let Some(decimals) = args.thresholds.score_precision {
+            raw.round_scores(decimals);
+        }
+        let csv = raw_csv(&raw);
+        match &args.output {
+            Some(filename) => write_or_exit(Path::new(filename), &csv),
+            None => print!("{}", csv),
+        }
+        return;
+    }
+    let prediction = match args.predict(pred, &img) {
+        Ok(prediction) => prediction,
+        Err(e) => {
+            eprintln!("Failed to tag '{}': {}", path.display(), e);
+            exit(1);
+        }
+    };
+
+    if args.sidecar {
+        write_sidecar(
+            args,
+            path.parent().unwrap_or(Path::new("")),
+            path,
+            &prediction,
+        );
+        return;
+    }
+    if let Some(dir) = &args.output_dir {
+        write_sidecar(args, dir, path, &prediction);
+        return;
+    }
+
+    let caption = args.caption(&prediction);
+
+    match (args.format, &args.output) {
+        // One line per image, so appended results stay parseable.
+        (OutputFormat::Json, Some(filename)) if args.append => write_output(
+            args,
+            Path::new(filename),
+            &format!("{}\n", to_json_line(&JsonOutput::new(&prediction, None))),
+        ),
+        (OutputFormat::Ndjson, Some(filename)) => write_output(
+            args,
+            Path::new(filename),
+            &format!("{}\n", to_json_line(&JsonOutput::new(&prediction, None))),
+        ),
+        (OutputFormat::Json, Some(filename)) => write_output(
+            args,
+            Path::new(filename),
+            &to_json(&JsonOutput::new(&prediction, None)),
+        ),
+        (OutputFormat::Json, None) => println!("{}", to_json(&JsonOutput::new(&prediction, None))),
+        (OutputFormat::Ndjson, None) => {
+            println!("{}", to_json_line(&JsonOutput::new(&prediction, None)))
+        }
+        (OutputFormat::Text, Some(filename)) if args.append => {
+            write_output(args, Path::new(filename), &format!("{}\n", caption))
+        }
+        (OutputFormat::Text, Some(filename)) => write_output(args, Path::new(filename), &caption),
+        (OutputFormat::Text, None) if args.tags_only => println!("{}", caption),
+        (OutputFormat::Text, None) => {
+            println!("Tags: {}", caption);
+            match &prediction.rating {
+                Some(rating) => println!("Rating: {:?}", rating),
+                None => println!("Rating: none"),
+            }
+            println!("Characters: {:?}", args.character_caption(&prediction));
+        }
+    }
+}
+
+/// Renders every score in `raw` as `tag,category,score` CSV rows.
+fn raw_csv(raw: &RawPrediction) -> String {
+    let mut category = vec!["other"; raw.tags.len()];
+    for (indices, name) in [
+        (&raw.rating, "rating"),
+        (&raw.general, "general"),
+        (&raw.character, "character"),
+    ] {
+        for &i in indices {
+            category[i] = name;
+        }
+    }
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer
+        .write_record(["tag", "category", "score"])
+        .expect("writing to a Vec cannot fail");
+    for ((tag, score), category) in raw.tags.iter().zip(category) {
+        writer
+            .write_record([tag.as_str(), category, &score.to_string()])
+            .expect("writing to a Vec cannot fail");
+    }
+    String::from_utf8(writer.into_inner().expect("writing to a Vec cannot fail"))
+        .expect("CSV built from strings is UTF-8")
+}
+
+/// Renders --report counts as `tag,count,mean_score` CSV rows, most
+/// frequent first and alphabetical among ties, with the means rounded to
+/// `precision` decimal places if given.
+fn report_csv(tag_counts: &HashMap<String, (usize, f32)>, precision: Option<u32>) -> String {
+    let mut rows: Vec<_> = tag_counts.iter().collect();
+    rows.sort_by(|(tag_a, (count_a, _)), (tag_b, (count_b, _))| {
+        count_b.cmp(count_a).then_with(|| tag_a.cmp(tag_b))
+    });
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer
+        .write_record(["tag", "count", "mean_score"])
+        .expect("writing to a Vec cannot fail");
+    for (tag, &(count, total)) in rows {
+        writer
+            .write_record([
+                tag.as_str(),
+                &count.to_string(),
+                &match precision {
+                    Some(decimals) => format!("{:.*}", decimals as usize, total / count as f32),
+                    None => (total / count as f32).to_string(),
+                },
+            ])
+            .expect("writing to a Vec cannot fail");
+    }
+    String::from_utf8(writer.into_inner().expect("writing to a Vec cannot fail"))
+        .expect("CSV built from strings is UTF-8")
+}
+
+/// Whether a directory walk should tag `path`: one of the `--ext`
+/// extensions, or by default any extension `image` can decode.
+fn has_image_extension(path: &Path, extensions: &[String]) -> bool {
+    let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+        return false;
+    };
+    if extensions.is_empty() {
+        ImageFormat::from_extension(ext).is_some_and(|format| format.reading_enabled())
+    } else {
+        extensions
+            .iter()
+            .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+}
+
+/// Recursively collects image files under `dir`, skipping hidden files and
+/// directories and anything without an image extension.
+fn collect_images(dir: &Path, extensions: &[String], found: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("skipping directory '{}': {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            debug!("skipping hidden '{}'", path.display());
+        } else if path.is_dir() {
+            collect_images(&path, extensions, found);
+        } else if has_image_extension(&path, extensions) {
+            found.push(path);
+        } else {
+            debug!("skipping '{}': not an image extension", path.display());
+        }
+    }
+}
+
+/// Writes `prediction` for the image at `path` to a sidecar file in `dir`,
+/// named after the image with the `--caption-ext` extension.
+fn write_sidecar(args: &Args, dir: &Path, path: &Path, prediction: &Prediction) {
+    let contents = match args.format {
+        OutputFormat::Text => args.caption(prediction),
+        OutputFormat::Json => to_json(&JsonOutput::new(prediction, None)),
+        OutputFormat::Ndjson => to_json_line(&JsonOutput::new(prediction, None)),
+    };
+    write_or_exit(&sidecar_path(args, dir, path), &contents);
+}
+
+/// The sidecar file in `dir` for the image at `path`.
+fn sidecar_path(args: &Args, dir: &Path, path: &Path) -> PathBuf {
+    let ext = match args.format {
+        OutputFormat::Text => "txt",
+        OutputFormat::Json | OutputFormat::Ndjson => "json",
+    };
+    let ext = args
+        .caption_ext
+        .as_deref()
+        .map(|ext| ext.trim_start_matches('.'))
+        .unwrap_or(ext);
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ext);
+    dir.join(name)
+}
+
+/// Where per-image results go when tagging several images. With --sidecar
+/// or --output-dir, each image gets its own sidecar file; `--output`
+/// collects the results into a single file.
+struct BatchOutput<'a> {
+    args: &'a Args,
+    sidecar_dir: Option<&'a Path>,
+    /// The directory inputs, whose layout is mirrored below `sidecar_dir`.
+    roots: Vec<&'a Path>,
+    lines: String,
+    json_results: Vec<serde_json::Value>,
+    /// Per tag, for --report: how many images kept it, and its total score.
+    tag_counts: HashMap<String, (usize, f32)>,
+    /// Where `--format ndjson` lines go as they are produced.
+    stream: Option<Box<dyn Write>>,
+}
+
+impl<'a> BatchOutput<'a> {
+    fn new(args: &'a Args) -> Self {
+        let sidecar_dir = args.output_dir.as_deref();
+        let stream: Option<Box<dyn Write>> = match (args.format, &args.output) {
+            (OutputFormat::Ndjson, _) if args.sidecar || sidecar_dir.is_some() => None,
+            (OutputFormat::Ndjson, Some(filename)) => {
+                Some(Box::new(open_output(args, Path::new(filename))))
+            }
+            (OutputFormat::Ndjson, None) => Some(Box::new(io::stdout())),
+            _ => None,
+        };
+        BatchOutput {
+            args,
+            sidecar_dir,
+            roots: args
+                .image
+                .iter()
+                .map(Path::new)
+                .filter(|path| path.is_dir())
+                .collect(),
+            lines: String::new(),
+            json_results: vec![],
+            tag_counts: HashMap::new(),
+            stream,
+        }
+    }
+
+    fn record(&mut self, path: &Path, prediction: &Prediction) {
+        if self.args.report.is_some() {
+            for (tag, score) in prediction.general.iter().chain(&prediction.characters) {
+                let entry = self.tag_counts.entry(tag.clone()).or_default();
+                entry.0 += 1;
+                entry.1 += score;
+            }
         }
+        let tags = self.args.caption(prediction);
+        if self.args.sidecar {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            return write_sidecar(self.args, dir, path, prediction);
+        }
+        match (self.sidecar_dir, self.args.format) {
+            (Some(out_dir), _) => {
+                let dir = out_dir.join(self.subdirectory(path));
+                if let Err(e) = fs::create_dir_all(&dir) {
+                    eprintln!("Failed to create '{}': {}", dir.display(), e);
+                    exit(1);
+                }
+                write_sidecar(self.args, &dir, path, prediction)
+            }
+            (None, OutputFormat::Ndjson) => {
+                let file = Some(path.display().to_string());
+                let line = to_json_line(&JsonOutput::new(prediction, file));
+                let stream = self.stream.as_mut().expect("opened for ndjson");
+                // Flush per line so a consumer on a pipe sees each result.
+                if let Err(e) = writeln!(stream, "{}", line).and_then(|()| stream.flush()) {
+                    eprintln!("Failed to write result: {}", e);
+                    exit(1);
+                }
+            }
+            (None, OutputFormat::Json) if self.args.append => {
+                let file = Some(path.display().to_string());
+                self.lines
+                    .push_str(&to_json_line(&JsonOutput::new(prediction, file)));
+                self.lines.push('\n');
+            }
+            (None, OutputFormat::Json) => {
+                let file = Some(path.display().to_string());
+                self.json_results
+                    .push(serde_json::to_value(JsonOutput::new(prediction, file)).unwrap());
+            }
+            (None, OutputFormat::Text) if self.args.output.is_some() => {
+                self.lines
+                    .push_str(&format!("{}: {}\n", path.display(), tags));
+            }
+            (None, OutputFormat::Text) if self.args.tags_only => println!("{}", tags),
+            (None, OutputFormat::Text) => println!("{}: {}", path.display(), tags),
+        }
+    }
+
+    /// The file `record` writes the result for `path` to, with --sidecar or
+    /// --output-dir.
+    fn result_path(&self, path: &Path) -> Option<PathBuf> {
+        let dir = if self.args.sidecar {
+            path.parent().unwrap_or(Path::new("")).to_path_buf()
+        } else {
+            self.sidecar_dir?.join(self.subdirectory(path))
+        };
+        Some(sidecar_path(self.args, &dir, path))
+    }
+
+    /// The directory of `path` relative to the directory input it was found
+    /// in, or nothing for images given directly.
+    fn subdirectory<'p>(&self, path: &'p Path) -> &'p Path {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        self.roots
+            .iter()
+            .find_map(|root| parent.strip_prefix(root).ok())
+            .unwrap_or(Path::new(""))
+    }
+
+    fn finish(mut self) {
+        if let Some(report) = &self.args.report {
+            write_or_exit(
+                report,
+                &report_csv(&self.tag_counts, self.args.thresholds.score_precision),
+            );
+        }
+        let output = self.args.output.as_deref();
+        if self.args.sidecar || self.stream.is_some() {
+            return;
+        }
+        if self.sidecar_dir.is_none() && self.args.format == OutputFormat::Json && !self.args.append
+        {
+            self.lines = to_json(&self.json_results);
+            if output.is_none() {
+                println!("{}", self.lines);
+            }
+        }
+        if let (None, Some(filename)) = (self.sidecar_dir, output) {
+            write_output(self.args, Path::new(filename), &self.lines);
+        }
+    }
+}
+
+fn tag_directory(pred: &mut Predictor, dir: &Path, args: &Args) {
+    let mut images = vec![];
+    collect_images(dir, &args.ext, &mut images);
+    images.sort();
+    tag_images(pred, &images, args);
+}
+
+/// Reads the paths in an `--input-list` file.
+fn read_input_list(list: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(fs::read_to_string(list)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// A preprocessed image waiting for the model, with what its debug timing
+/// line reports.
+struct Prepared {
+    input: Array4<f32>,
+    /// The decoded image's width and height, or `None` for a cache hit.
+    size: Option<(u32, u32)>,
+    /// How long reading, decoding and preprocessing took.
+    took: Duration,
+}
+
+impl Prepared {
+    fn new(input: Array4<f32>, img: Option<&DynamicImage>, start: Instant) -> Self {
+        Prepared {
+            input,
+            size: img.map(|img| (img.width(), img.height())),
+            took: start.elapsed(),
+        }
+    }
+}
+
+/// Tags `images` in order, for a directory or an `--input-list`. Each
+/// image's preprocessing and inference times are logged at debug level, and
+/// unless `--quiet`, a summary of the run is printed to stderr at the end.
+fn tag_images(pred: &mut Predictor, images: &[PathBuf], args: &Args) {
+    let run_start = Instant::now();
+    if args.dump_preprocessed.is_some()
+        || !args.threshold_sweep.is_empty()
+        || args.histogram
+        || args.tta
+        || args.hflip_tta
+    {
+        eprintln!(
+            "--dump-preprocessed, --threshold-sweep, --histogram, --tta and --hflip-tta only \
+             support a single image"
+        );
+        exit(1);
+    }
+    let mut output = BatchOutput::new(args);
+    // With --skip-existing, only the images without a result yet.
+    let mut skipped = 0;
+    let pending: Vec<PathBuf>;
+    let images = if args.skip_existing {
+        pending = images
+            .iter()
+            .filter(|path| {
+                let done = output
+                    .result_path(path)
+                    .is_some_and(|result| result.exists());
+                if done {
+                    debug!("skipping '{}': it has a result", path.display());
+                    skipped += 1;
+                }
+                !done
+            })
+            .cloned()
+            .collect();
+        &pending[..]
+    } else {
+        images
+    };
+    if skipped > 0 {
+        info!("skipping {} image(s) that already have results", skipped);
+    }
+    load_or_exit(pred);
+    let preprocessor = pred.preprocessor().expect("loaded above");
+    let pool = match rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.into())
+        .build()
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("Failed to start {} worker threads: {}", args.jobs, e);
+            exit(1);
+        }
+    };
+
+    let cache = args.tensor_cache.as_deref().map(|dir| {
+        TensorCache::new(dir, &preprocessor, args.decode_options()).unwrap_or_else(|e| {
+            eprintln!("Failed to open tensor cache '{}': {}", dir.display(), e);
+            exit(1);
+        })
+    });
+
+    let progress = if args.no_progress || !io::stderr().is_terminal() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(images.len() as u64)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {pos}/{len} images ({per_sec}, ETA {eta}){msg}",
+                )
+                .expect("valid progress template"),
+            )
+            .with_message(if skipped > 0 {
+                format!(", {} skipped", skipped)
+            } else {
+                String::new()
+            })
+    };
+    // Print warnings through the bar so they don't garble it.
+    let warn = |path: &Path, e: &dyn std::fmt::Display| {
+        progress.suspend(|| warn!("skipping '{}': {}", path.display(), e))
+    };
+
+    // Decode and preprocess a few images per worker in parallel, then run
+    // them through the model a batch at a time since the session isn't shared.
+    let batch_size = usize::from(args.batch_size);
+    let mut done = 0;
+    let (mut tagged, mut failed, mut tags) = (0, 0, 0);
+    let chunk_len = (usize::from(args.jobs) * 4).div_ceil(batch_size) * batch_size;
+    for chunk in images.chunks(chunk_len) {
+        let inputs: Vec<Result<Prepared, String>> = pool.install(|| {
+            chunk
+                .par_iter()
+                .map(|path| {
+                    let start = Instant::now();
+                    // Only files have stable contents to key the cache on.
+                    let cached = cache
+                        .as_ref()
+                        .filter(|_| path != Path::new("-") && !is_url(path));
+                    let Some(cache) = cached else {
+                        let img = read_image(path, args.decode_options())?;
+                        let input = preprocessor.prepare(&img).map_err(|e| e.to_string())?;
+                        return Ok(Prepared::new(input, Some(&img), start));
+                    };
+                    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+                    if let Some(input) = cache.get(&bytes) {
+                        return Ok(Prepared::new(input, None, start));
+                    }
+                    let img = decode_image(&bytes, args.decode_options())?;
+                    let input = preprocessor.prepare(&img).map_err(|e| e.to_string())?;
+                    if let Err(e) = cache.put(&bytes, &input) {
+                        progress.suspend(|| warn!("failed to cache '{}': {}", path.display(), e));
+                    }
+                    Ok(Prepared::new(input, Some(&img), start))
+                })
+                .collect()
+        });
+        let mut ready = vec![];
+        for (path, input) in chunk.iter().zip(inputs) {
+            match input {
+                Ok(input) => ready.push((path, input)),
+                Err(e) => {
+                    warn(path, &e);
+                    progress.inc(1);
+                    done += 1;
+                    failed += 1;
+                }
+            }
+        }
+        let mut ready = ready.into_iter().peekable();
+        while ready.peek().is_some() {
+            let (paths, prepared): (Vec<_>, Vec<_>) = ready.by_ref().take(batch_size).unzip();
+            let count = paths.len();
+            let (batch, timings): (Vec<_>, Vec<_>) = prepared
+                .into_iter()
+                .map(|prepared| (prepared.input, (prepared.size, prepared.took)))
+                .unzip();
+            let start = Instant::now();
+            match args.thresholds.predict_batch_prepared(pred, batch) {
+                Ok(predictions) => {
+                    // The batch runs as one model call, so each image is
+                    // charged an equal share of it.
+                    let inference = start.elapsed() / count as u32;
+                    for ((path, prediction), (size, took)) in
+                        paths.into_iter().zip(predictions).zip(timings)
+                    {
+                        done += 1;
+                        progress.suspend(|| {
+                            debug!(
+                                "{} ({}): preprocess {:.1} ms, inference {:.1} ms; {}/{} ({:.0}%)",
+                                path.display(),
+                                size.map_or("cached".to_string(), |(w, h)| format!("{}x{}", w, h)),
+                                took.as_secs_f64() * 1e3,
+                                inference.as_secs_f64() * 1e3,
+                                done,
+                                images.len(),
+                                100.0 * done as f64 / images.len() as f64
+                            )
+                        });
+                        tagged += 1;
+                        tags += prediction.general.len() + prediction.characters.len();
+                        output.record(path, &prediction);
+                    }
+                }
+                Err(e) => {
+                    for path in paths {
+                        warn(path, &e);
+                    }
+                    done += count;
+                    failed += count;
+                }
+            }
+            progress.inc(count as u64);
+        }
+    }
+
+    progress.finish_and_clear();
+    output.finish();
+    if !args.quiet {
+        eprintln!(
+            "Tagged {} image(s), skipped {}, failed {}; {} tag(s) in {:.1}s",
+            tagged,
+            skipped,
+            failed,
+            tags,
+            run_start.elapsed().as_secs_f64()
+        );
     }
 }
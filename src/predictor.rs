@@ -1,16 +1,51 @@
 use clap::ValueEnum;
 use csv::ReaderBuilder;
-use hf_hub::api::sync::Api;
-use image::{DynamicImage, GenericImage, RgbaImage};
-use ndarray::Array4;
-use ndarray::Ix2;
-use ort::{ep, session::Session, value::Tensor};
+#[cfg(feature = "hub")]
+use hf_hub::{
+    api::sync::{Api, ApiError},
+    Cache, Repo, RepoType,
+};
+use image::{DynamicImage, GenericImage, ImageBuffer, Pixel, Rgb, Rgb32FImage, RgbImage};
+#[cfg(feature = "hub")]
+use log::warn;
+use log::{debug, info};
+use ndarray::{Array4, Axis, Ix2};
+use ort::session::{builder::GraphOptimizationLevel, Session};
+use ort::{
+    ep,
+    value::{Tensor, TensorElementType},
+};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+#[cfg(feature = "hub")]
+use std::{sync::OnceLock, time::Duration};
+
+use crate::error::{Result, TaggerError};
 
 // ── WD14 constants ────────────────────────────────────────────────────────────
+// Each tagger lives in a subdirectory of the embeddings repo named after its
+// original SmilingWolf repo, e.g. `SmilingWolf/wd-swinv2-tagger-v3/model.onnx`.
 pub const WD14_REPO: &str = "deepghs/wd14_tagger_with_embeddings";
-pub const WD14_MODEL_FILE: &str = "SmilingWolf/wd-vit-large-tagger-v3/model.onnx";
-pub const WD14_TAG_CSV: &str = "SmilingWolf/wd-vit-large-tagger-v3/tags_info.csv";
+pub const WD14_DEFAULT_MODEL: &str = "SmilingWolf/wd-vit-large-tagger-v3";
+pub const WD14_MODEL_FILE: &str = "model.onnx";
+pub const WD14_TAG_CSV: &str = "tags_info.csv";
+
+/// Taggers known to be in [`WD14_REPO`], with their square input edge.
+pub const WD14_KNOWN_MODELS: &[(&str, u32)] = &[
+    ("SmilingWolf/wd-vit-large-tagger-v3", 448),
+    ("SmilingWolf/wd-eva02-large-tagger-v3", 448),
+    ("SmilingWolf/wd-vit-tagger-v3", 448),
+    ("SmilingWolf/wd-swinv2-tagger-v3", 448),
+    ("SmilingWolf/wd-convnext-tagger-v3", 448),
+    ("SmilingWolf/wd-v1-4-vit-tagger-v2", 448),
+    ("SmilingWolf/wd-v1-4-swinv2-tagger-v2", 448),
+    ("SmilingWolf/wd-v1-4-convnext-tagger-v2", 448),
+    ("SmilingWolf/wd-v1-4-convnextv2-tagger-v2", 448),
+    ("SmilingWolf/wd-v1-4-moat-tagger-v2", 448),
+];
 
 // ── DINOv3 constants ──────────────────────────────────────────────────────────
 // ONNX weights live in the silveroxides conversion repo.
@@ -33,12 +68,154 @@ const IMAGENET_STD: [f32; 3] = [0.229, 0.224, 0.225];
 const DINO_PATCH_SIZE: u32 = 16;
 const DINO_MAX_SIZE: u32 = 1024;
 
+/// Unthresholded model output for one image.
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct RawPrediction {
+    /// Every tag with its score, in model output order.
+    pub tags: Vec<(String, f32)>,
+    /// Positions in `tags` of the rating, general and character tags.
+    pub rating: Vec<usize>,
+    pub general: Vec<usize>,
+    pub character: Vec<usize>,
+    /// Image embedding, or empty if the model doesn't provide one.
+    pub embedding: Vec<f32>,
+}
+
+/// Result of tagging one image. Tag lists hold `(name, score)` pairs.
 #[derive(Serialize, Debug, Deserialize, Clone)]
-pub struct OutputData {
+pub struct Prediction {
+    /// General tags above the threshold, highest score first unless
+    /// [`ThresholdConfig::general_order`] says otherwise.
     pub general: Vec<(String, f32)>,
-    pub rating: (String, f32),
+    /// `general` tag names joined with `", "`, ready to use as a caption.
+    pub general_string: String,
+    /// The highest-scoring rating tag among `ratings`, or `None` if the
+    /// rating threshold left none.
+    pub rating: Option<(String, f32)>,
+    /// Rating tags selected by [`ThresholdConfig::rating`] (by default all
+    /// of them), highest score first.
+    pub ratings: Vec<(String, f32)>,
+    /// Character tags above the threshold, highest score first.
     pub characters: Vec<(String, f32)>,
+    /// Image embedding, or empty if the model doesn't provide one.
     pub embedding: Vec<f32>,
+    /// The score cut-offs that produced `ratings`, `general` and
+    /// `characters`.
+    pub thresholds: AppliedThresholds,
+    /// Width and height of the input the model ran on: the model's (or the
+    /// overridden) square size for WD14, the resized image for DINOv3.
+    #[serde(default)]
+    pub input_size: Option<(usize, usize)>,
+}
+
+/// The numeric threshold each category was cut at: the fixed value, or the
+/// one MCut picked (after its floor). `None` for top-k selection, which has
+/// no single cut-off, or for a category that is [`Threshold::Off`]. Tags
+/// must score strictly above the threshold, except with
+/// [`Threshold::Relative`], where scoring exactly the cut-off is enough.
+#[derive(Serialize, Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct AppliedThresholds {
+    pub rating: Option<f32>,
+    pub general: Option<f32>,
+    pub character: Option<f32>,
+}
+
+impl RawPrediction {
+    /// Rounds every score to `decimals` decimal places, for stable output.
+    pub fn round_scores(&mut self, decimals: u32) {
+        for (_, score) in &mut self.tags {
+            *score = round_score(*score, decimals);
+        }
+    }
+
+    /// The score of each of `tags` as the thresholds of `thresholds`
+    /// compare it, after its temperature and rating normalisation.
+    pub fn adjusted_scores(&self, thresholds: &ThresholdConfig) -> Vec<f32> {
+        let scores: Vec<f32> = self.tags.iter().map(|(_, score)| *score).collect();
+        adjusted_scores(&scores, &self.rating, thresholds).into_owned()
+    }
+
+    /// Selects tags as [`Predictor::predict`] would have, without running
+    /// the model again, e.g. to compare several thresholds on one image.
+    pub fn threshold(&self, thresholds: &ThresholdConfig, filter: &TagFilter) -> Prediction {
+        let (names, scores): (Vec<String>, Vec<f32>) = self.tags.iter().cloned().unzip();
+        let labels = Labels {
+            names,
+            rating: self.rating.clone(),
+            general: self.general.clone(),
+            character: self.character.clone(),
+        };
+        build_output(&scores, self.embedding.clone(), &labels, thresholds, filter)
+    }
+}
+
+/// Most decimal places worth rounding a score to: an `f32` holds no more
+/// than about nine significant digits.
+pub const MAX_SCORE_PRECISION: u32 = 9;
+
+/// `score` to `decimals` places, up to [`MAX_SCORE_PRECISION`].
+fn round_score(score: f32, decimals: u32) -> f32 {
+    let scale = 10f64.powi(decimals.min(MAX_SCORE_PRECISION) as i32);
+    ((score as f64 * scale).round() / scale) as f32
+}
+
+impl Prediction {
+    /// Rounds every score and applied threshold to `decimals` decimal
+    /// places, for stable output. Tagging is unaffected: call this on a
+    /// finished prediction.
+    pub fn round_scores(&mut self, decimals: u32) {
+        let tags = self
+            .general
+            .iter_mut()
+            .chain(&mut self.ratings)
+            .chain(&mut self.characters)
+            .chain(&mut self.rating);
+        for (_, score) in tags {
+            *score = round_score(*score, decimals);
+        }
+        let thresholds = &mut self.thresholds;
+        for threshold in [
+            &mut thresholds.rating,
+            &mut thresholds.general,
+            &mut thresholds.character,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *threshold = round_score(*threshold, decimals);
+        }
+    }
+
+    /// Rescales `ratings` (and `rating`) to sum to 1, turning the
+    /// independent per-class scores into a distribution. Only the kept
+    /// ratings count, so after a rating threshold a lone survivor always
+    /// scores 1; [`ThresholdConfig::softmax_ratings`] normalises all of
+    /// them before thresholding instead.
+    pub fn normalise_ratings(&mut self) {
+        let total: f32 = self.ratings.iter().map(|(_, score)| score).sum();
+        if total <= 0.0 {
+            return;
+        }
+        for (_, score) in &mut self.ratings {
+            *score /= total;
+        }
+        if let Some((_, score)) = &mut self.rating {
+            *score /= total;
+        }
+    }
+
+    /// Puts the top rating's name in front of `general_string`, as in
+    /// `general, 1girl, solo` captions for training.
+    pub fn prepend_rating(&mut self) {
+        let Some((rating, _)) = &self.rating else {
+            return;
+        };
+        self.general_string = if self.general_string.is_empty() {
+            rating.clone()
+        } else {
+            format!("{}, {}", rating, self.general_string)
+        };
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
@@ -51,16 +228,313 @@ pub enum ModelKind {
     DINOv3Q8,
 }
 
+/// ONNX Runtime execution provider to run the model on. Every choice other
+/// than `Cpu` falls back to the CPU if the provider can't be registered.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Device {
+    #[value(name = "cpu")]
+    Cpu,
+    #[value(name = "cuda")]
+    Cuda,
+    #[value(name = "tensorrt")]
+    TensorRt,
+    #[value(name = "directml")]
+    DirectMl,
+}
+
+/// ONNX Runtime graph optimisation level, from none to every rewrite. ONNX
+/// Runtime applies `all` unless told otherwise; a lower level can help an
+/// int8-quantized model whose fused kernels are slow or missing on a
+/// provider.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OptimizationLevel {
+    #[value(name = "disable")]
+    Disable,
+    /// Rewrites that only remove redundant work, such as constant folding.
+    #[value(name = "basic")]
+    Basic,
+    /// Also complex node fusions.
+    #[value(name = "extended")]
+    Extended,
+    /// Also memory layout changes.
+    #[value(name = "all")]
+    All,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+            OptimizationLevel::All => GraphOptimizationLevel::All,
+        }
+    }
+}
+
+/// Resampling filter used to scale images to the model's input size.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum ResizeFilter {
+    #[value(name = "nearest")]
+    Nearest,
+    #[value(name = "bilinear")]
+    Bilinear,
+    #[default]
+    #[value(name = "catmull-rom")]
+    CatmullRom,
+    #[value(name = "gaussian")]
+    Gaussian,
+    #[value(name = "lanczos3")]
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        use image::imageops::FilterType;
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Bilinear => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Channel order a WD14 model expects its pixels in.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum ChannelOrder {
+    /// Blue first, as OpenCV decodes images. The reference WD14 exports
+    /// were trained this way.
+    #[default]
+    #[value(name = "bgr")]
+    Bgr,
+    #[value(name = "rgb")]
+    Rgb,
+}
+
+impl ChannelOrder {
+    /// Reads the `channel_order` metadata entry some exports carry.
+    fn from_metadata(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "bgr" => Some(ChannelOrder::Bgr),
+            "rgb" => Some(ChannelOrder::Rgb),
+            _ => None,
+        }
+    }
+}
+
+/// How tag names from the label files are written in predictions.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum TagStyle {
+    /// Underscores become spaces (`long hair`), except in names made only
+    /// of punctuation and digits such as `^_^` or `0_0` — the WD14
+    /// convention.
+    #[default]
+    #[value(name = "spaces")]
+    Spaces,
+    /// Exactly as written in the label file (`long_hair`).
+    #[value(name = "keep")]
+    Keep,
+    /// As `spaces`, with parentheses escaped (`fate \(series\)`) for
+    /// prompt and training tools that treat them as syntax.
+    #[value(name = "escape-parens")]
+    EscapeParens,
+}
+
+impl TagStyle {
+    /// `name` as written in this style.
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            TagStyle::Keep => name.to_string(),
+            TagStyle::Spaces => normalise_tag(name),
+            TagStyle::EscapeParens => escape_parens(&normalise_tag(name)),
+        }
+    }
+
+    /// Like [`TagStyle::apply`], reusing `name`'s allocation when the name
+    /// doesn't change, as for emoticons or [`TagStyle::Keep`].
+    fn apply_owned(self, name: String) -> String {
+        match self {
+            TagStyle::Keep => name,
+            TagStyle::Spaces if is_emoticon(&name) => name,
+            _ => self.apply(&name),
+        }
+    }
+}
+
+/// Order of the general tags in [`Prediction::general`] and
+/// [`Prediction::general_string`].
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum TagOrder {
+    /// Highest score first.
+    #[default]
+    #[value(name = "score")]
+    Score,
+    /// Alphabetical by tag name.
+    #[value(name = "alpha")]
+    Alpha,
+    /// The order the tags appear in the model's tag list.
+    #[value(name = "none")]
+    None,
+}
+
+/// How WD14 pixel values are scaled before they are fed to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum Normalize {
+    /// Raw values in 0.0..=255.0, as the reference exports expect.
+    #[default]
+    #[value(name = "none")]
+    None,
+    /// Divided by 255, into 0.0..=1.0.
+    #[value(name = "zero_one")]
+    ZeroOne,
+    /// Divided by 255, then standardised with the ImageNet mean and
+    /// standard deviation of each channel.
+    #[value(name = "imagenet")]
+    Imagenet,
+}
+
+impl Normalize {
+    /// Scales `value` (0.0..=255.0) of RGB channel `channel`.
+    fn apply(self, value: f32, channel: usize) -> f32 {
+        match self {
+            Normalize::None => value,
+            Normalize::ZeroOne => value / 255.0,
+            Normalize::Imagenet => (value / 255.0 - IMAGENET_MEAN[channel]) / IMAGENET_STD[channel],
+        }
+    }
+}
+
+/// How a non-square image is made square for a WD14 model.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum Fit {
+    /// Centre the image on a square canvas of the pad colour, keeping its
+    /// aspect ratio. This is how the WD14 models were trained.
+    #[default]
+    #[value(name = "pad")]
+    Pad,
+    /// Resize straight to the square input, distorting the aspect ratio.
+    /// Only worth it for pipelines that trained on stretched images; expect
+    /// less accurate tags for very wide or tall images otherwise.
+    #[value(name = "stretch")]
+    Stretch,
+    /// Keep the largest centred square of the image, so no padding border
+    /// can be mistaken for content (e.g. tagged `border` or `letterboxed`).
+    /// Whatever lies outside it goes untagged: only the middle third of a
+    /// 3:1 panorama is seen.
+    #[value(name = "crop")]
+    Crop,
+}
+
+/// One row of a WD14 `tags_info.csv` / `selected_tags.csv` file.
 #[derive(Debug, Deserialize)]
-struct Wd14TagRow {
-    name: String,
-    category: u8,
+pub struct Wd14TagRow {
+    pub name: String,
+    pub category: u8,
+}
+
+/// Which bucket of [`Labels`] a tag goes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Rating,
+    General,
+    Character,
+    /// Left out of every bucket, so never reported.
+    Ignore,
+}
+
+/// Maps the numeric categories of a tag list to [`Labels`] buckets.
+///
+/// The default is WD14's: 9 = rating, 0 = general, 4 = character. Other
+/// categories (such as 1 = artist or 3 = copyright) are ignored unless
+/// mapped with [`CategoryMap::set`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CategoryMap(HashMap<u8, Category>);
+
+impl Default for CategoryMap {
+    fn default() -> Self {
+        CategoryMap(HashMap::from([
+            (9, Category::Rating),
+            (0, Category::General),
+            (4, Category::Character),
+        ]))
+    }
+}
+
+impl CategoryMap {
+    /// Puts tags of category `code` in `category`.
+    pub fn set(&mut self, code: u8, category: Category) {
+        self.0.insert(code, category);
+    }
+
+    /// The bucket for tags of category `code`.
+    pub fn get(&self, code: u8) -> Category {
+        self.0.get(&code).copied().unwrap_or(Category::Ignore)
+    }
+}
+
+/// Reads category overrides from a CSV file of `code,category` rows without
+/// a header, e.g. `3,general` or `9,ignore`; lines starting with `#` are
+/// skipped. Codes not in the file keep their [`CategoryMap::default`]
+/// bucket.
+pub fn load_category_map(path: &Path) -> Result<CategoryMap> {
+    let csv_err = |source| TaggerError::Csv {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(false)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(csv_err)?;
+    let mut map = CategoryMap::default();
+    for row in rdr.deserialize() {
+        let (code, category): (u8, Category) = row.map_err(csv_err)?;
+        map.set(code, category);
+    }
+    Ok(map)
+}
+
+/// Tag vocabulary of a model, bucketed by category.
+///
+/// `names` is indexed by model output position; the three index vectors
+/// select which positions belong to each category.
+#[derive(Debug, Clone, Default)]
+pub struct Labels {
+    pub names: Vec<String>,
+    pub rating: Vec<usize>,
+    pub general: Vec<usize>,
+    pub character: Vec<usize>,
+}
+
+impl Labels {
+    /// Empty buckets, with room for `tags` names.
+    fn with_capacity(tags: usize) -> Self {
+        Labels {
+            names: Vec::with_capacity(tags),
+            ..Labels::default()
+        }
+    }
+
+    /// Adds output position `idx` to the bucket for `category`.
+    fn bucket(&mut self, idx: usize, category: Category) {
+        match category {
+            Category::Rating => self.rating.push(idx),
+            Category::General => self.general.push(idx),
+            Category::Character => self.character.push(idx),
+            Category::Ignore => {}
+        }
+    }
 }
 
 /// Expected shape of `tagger_vocab_with_categories.json`:
 ///   { "idx2tag": ["tag", ...], "idx2cat": [0, 9, 4, ...] }
 ///
 /// Category codes mirror WD14: 0 = general, 4 = character, 9 = rating.
+/// They are bucketed through a [`CategoryMap`] like those of a tag CSV.
 /// If `idx2cat` is absent (plain vocab file), every tag is treated as general.
 #[derive(Debug, Deserialize)]
 struct DinoVocab {
@@ -69,119 +543,676 @@ struct DinoVocab {
     idx2cat: Vec<u8>,
 }
 
-fn load_wd14_labels(
-    repo_name: &str,
-    tag_file: &str,
-) -> (Vec<String>, Vec<usize>, Vec<usize>, Vec<usize>) {
-    let api = Api::new().unwrap();
-    let repo = api.model(repo_name.to_string());
-    let path = repo.get(tag_file).unwrap();
-    let mut rdr = ReaderBuilder::new()
-        .has_headers(true)
-        .from_path(path)
-        .unwrap();
+/// Failed downloads are retried [`Predictor::with_max_retries`] times, after
+/// waiting this long and then twice as long before each further attempt.
+#[cfg(feature = "hub")]
+const RETRY_DELAY: Duration = Duration::from_secs(1);
 
-    let (mut names, mut ratings, mut general, mut character) = (vec![], vec![], vec![], vec![]);
-
-    for (idx, res) in rdr.deserialize().enumerate() {
-        let row: Wd14TagRow = res.unwrap();
-        let name = normalise_tag(&row.name);
-        match row.category {
-            9 => ratings.push(idx),
-            0 => general.push(idx),
-            4 => character.push(idx),
-            _ => {}
-        }
-        names.push(name);
-    }
-    (names, ratings, general, character)
-}
-
-fn load_dino_labels(
-    base_repo: &str,
-    vocab_file: &str,
-) -> (Vec<String>, Vec<usize>, Vec<usize>, Vec<usize>) {
-    let api = Api::new().unwrap();
-    let repo = api.model(base_repo.to_string());
-    let path = repo.get(vocab_file).unwrap();
-    let text = std::fs::read_to_string(path).unwrap();
-    let vocab: DinoVocab = serde_json::from_str(&text).unwrap();
-
-    let (mut names, mut ratings, mut general, mut character) = (vec![], vec![], vec![], vec![]);
-
-    for (idx, tag) in vocab.idx2tag.iter().enumerate() {
-        if !vocab.idx2cat.is_empty() {
-            match vocab.idx2cat[idx] {
-                9 => ratings.push(idx),
-                0 => general.push(idx),
-                4 => character.push(idx),
-                _ => {}
-            }
+/// Where model and label files come from: the Hugging Face hub (which reuses
+/// its local cache when it can), or only the local cache when offline.
+#[cfg(feature = "hub")]
+enum Hub {
+    Online { api: Api, max_retries: u32 },
+    Offline(Cache),
+}
+
+#[cfg(feature = "hub")]
+impl Hub {
+    fn new(offline: bool, max_retries: u32) -> Result<Self> {
+        if offline {
+            Ok(Hub::Offline(Cache::from_env()))
         } else {
+            let api = Api::new().map_err(TaggerError::Hub)?;
+            Ok(Hub::Online { api, max_retries })
+        }
+    }
+
+    /// Resolves `file` from the hub repo `repo_name` to a local path, at
+    /// `revision` (a branch, tag or commit) or else the default branch.
+    fn get(&self, repo_name: &str, revision: Option<&str>, file: &str) -> Result<PathBuf> {
+        let (repo, repo_name) = match revision {
+            Some(revision) => (
+                Repo::with_revision(repo_name.to_string(), RepoType::Model, revision.to_string()),
+                format!("{}@{}", repo_name, revision),
+            ),
+            None => (Repo::model(repo_name.to_string()), repo_name.to_string()),
+        };
+        match self {
+            Hub::Online { api, max_retries } => {
+                let repo = api.repo(repo);
+                let mut delay = RETRY_DELAY;
+                let mut attempt = 0;
+                loop {
+                    match repo.get(file) {
+                        Ok(path) => return Ok(path),
+                        Err(e) if attempt < *max_retries && is_transient(&e) => {
+                            warn!(
+                                "downloading {}/{} failed ({}); retrying in {:?}",
+                                repo_name, file, e, delay
+                            );
+                            std::thread::sleep(delay);
+                            delay *= 2;
+                            attempt += 1;
+                        }
+                        Err(source) => {
+                            return Err(TaggerError::Download {
+                                repo: repo_name,
+                                file: file.to_string(),
+                                source,
+                            })
+                        }
+                    }
+                }
+            }
+            Hub::Offline(cache) => {
+                cache
+                    .repo(repo)
+                    .get(file)
+                    .ok_or_else(|| TaggerError::NotCached {
+                        repo: repo_name,
+                        file: file.to_string(),
+                    })
+            }
+        }
+    }
+}
+
+/// Whether retrying a failed download could help: I/O errors, failed
+/// connections and timeouts, and 429 or 5xx responses. Other 4xx responses,
+/// such as a 404 for a file or repo that doesn't exist, are final.
+#[cfg(feature = "hub")]
+fn is_transient(e: &ApiError) -> bool {
+    use ureq::ErrorKind;
+    match e {
+        ApiError::RequestError(e) => match e.as_ref() {
+            ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+            ureq::Error::Transport(transport) => matches!(
+                transport.kind(),
+                ErrorKind::Dns
+                    | ErrorKind::ConnectionFailed
+                    | ErrorKind::Io
+                    | ErrorKind::ProxyConnect
+            ),
+        },
+        ApiError::IoError(_) => true,
+        ApiError::TooManyRetries(e) => is_transient(e),
+        _ => false,
+    }
+}
+
+/// Parses a WD14 tag CSV and buckets it by category.
+///
+/// Tag names are written in `style`, and `categories` picks each tag's
+/// bucket from its `category` column.
+pub fn load_wd14_labels(path: &Path, style: TagStyle, categories: &CategoryMap) -> Result<Labels> {
+    let io_err = |source| TaggerError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = std::fs::File::open(path).map_err(io_err)?;
+    let len = file.metadata().map_err(io_err)?.len();
+    let rdr = ReaderBuilder::new().has_headers(true).from_reader(file);
+    parse_wd14_labels(rdr, len, path, style, categories)
+}
+
+/// The `WD14_EMBEDDED_TAGS` path, failing the build with a clear message
+/// when it isn't set.
+#[cfg(feature = "embedded-tags")]
+macro_rules! embedded_tags_path {
+    () => {
+        env!(
+            "WD14_EMBEDDED_TAGS",
+            "the `embedded-tags` feature needs WD14_EMBEDDED_TAGS set to the absolute path \
+             of a WD14 tag CSV, e.g. tags_info.csv"
+        )
+    };
+}
+
+/// Tag CSV compiled into the crate by the `embedded-tags` feature, read at
+/// build time from the file named by the `WD14_EMBEDDED_TAGS` environment
+/// variable (an absolute path).
+#[cfg(feature = "embedded-tags")]
+pub const EMBEDDED_WD14_TAGS: &str = include_str!(embedded_tags_path!());
+
+/// The WD14 tagger [`EMBEDDED_WD14_TAGS`] belongs to, from the
+/// `WD14_EMBEDDED_MODEL` environment variable at build time, or else
+/// [`WD14_DEFAULT_MODEL`]. Other taggers fetch their own tag list.
+#[cfg(feature = "embedded-tags")]
+pub const EMBEDDED_WD14_MODEL: &str = match option_env!("WD14_EMBEDDED_MODEL") {
+    Some(model) => model,
+    None => WD14_DEFAULT_MODEL,
+};
+
+/// Parses [`EMBEDDED_WD14_TAGS`]; `style` and `categories` work as for
+/// [`load_wd14_labels`].
+#[cfg(feature = "embedded-tags")]
+pub fn embedded_wd14_labels(style: TagStyle, categories: &CategoryMap) -> Result<Labels> {
+    let rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(EMBEDDED_WD14_TAGS.as_bytes());
+    parse_wd14_labels(
+        rdr,
+        EMBEDDED_WD14_TAGS.len() as u64,
+        Path::new(embedded_tags_path!()),
+        style,
+        categories,
+    )
+}
+
+/// Shared by [`load_wd14_labels`] and the embedded CSV; `bytes` is the
+/// CSV's length, to reserve room for its rows, and `path` is only used in
+/// errors.
+fn parse_wd14_labels<R: std::io::Read>(
+    mut rdr: csv::Reader<R>,
+    bytes: u64,
+    path: &Path,
+    style: TagStyle,
+    categories: &CategoryMap,
+) -> Result<Labels> {
+    let csv_err = |source| TaggerError::Csv {
+        path: path.to_path_buf(),
+        source,
+    };
+    #[cfg(test)]
+    tests::LABEL_PARSES.with(|parses| parses.set(parses.get() + 1));
+    let mut labels = Labels::with_capacity((bytes / WD14_ROW_BYTES) as usize);
+    for (idx, row) in rdr.deserialize().enumerate() {
+        let row: Wd14TagRow = row.map_err(csv_err)?;
+        labels.bucket(idx, categories.get(row.category));
+        labels.names.push(style.apply_owned(row.name));
+    }
+    Ok(labels)
+}
+
+/// Typical length of a WD14 tag CSV row such as `1234,long_hair,0,5678`,
+/// to size [`Labels`] from the file's length.
+const WD14_ROW_BYTES: u64 = 28;
+
+/// Parses a DINOv3 vocabulary JSON and buckets it by category. `style`
+/// and `categories` work as for [`load_wd14_labels`].
+pub fn load_dino_labels(path: &Path, style: TagStyle, categories: &CategoryMap) -> Result<Labels> {
+    let json = std::fs::read(path).map_err(|source| TaggerError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse_dino_labels(&json, path, style, categories)
+}
+
+/// Shared by [`load_dino_labels`] and in-memory vocabularies; `path` is
+/// only used in errors.
+fn parse_dino_labels(
+    json: &[u8],
+    path: &Path,
+    style: TagStyle,
+    categories: &CategoryMap,
+) -> Result<Labels> {
+    let vocab: DinoVocab = serde_json::from_slice(json).map_err(|source| TaggerError::Vocab {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    if !vocab.idx2cat.is_empty() && vocab.idx2cat.len() != vocab.idx2tag.len() {
+        return Err(TaggerError::UnsupportedModel(format!(
+            "DINOv3 vocabulary '{}' has {} tags but {} categories",
+            path.display(),
+            vocab.idx2tag.len(),
+            vocab.idx2cat.len()
+        )));
+    }
+
+    let mut labels = Labels::with_capacity(vocab.idx2tag.len());
+    for (idx, tag) in vocab.idx2tag.into_iter().enumerate() {
+        match vocab.idx2cat.get(idx) {
+            Some(&code) => labels.bucket(idx, categories.get(code)),
             // No category data: bucket everything as general
-            general.push(idx);
+            None => labels.general.push(idx),
+        }
+        labels.names.push(style.apply_owned(tag));
+    }
+    Ok(labels)
+}
+
+/// How the tags of one category are selected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    /// Keep tags scoring above this value.
+    Fixed(f32),
+    /// Keep tags above the MCut threshold picked per image, but never use a
+    /// threshold below `floor`.
+    Mcut { floor: f32 },
+    /// Keep the k highest-scoring tags.
+    TopK(usize),
+    /// Keep tags scoring at least this fraction of the category's top score,
+    /// e.g. 0.5 for everything at least half as confident as the best tag.
+    Relative(f32),
+    /// Keep no tags, skipping the category entirely.
+    Off,
+}
+
+/// Per-category tag selection for [`Predictor::predict`].
+///
+/// The default keeps every rating and reproduces the reference thresholds of
+/// 0.35 for general tags and 0.85 for characters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThresholdConfig {
+    /// Which ratings go into [`Prediction::ratings`]; [`Prediction::rating`]
+    /// is the top one, if any.
+    pub rating: Threshold,
+    pub general: Threshold,
+    pub character: Threshold,
+    /// How the kept general tags are ordered.
+    pub general_order: TagOrder,
+    /// Most general tags to keep after thresholding, dropping the lowest
+    /// scores; `None` for no limit.
+    pub max_general: Option<usize>,
+    /// Most character tags to keep after thresholding, likewise.
+    pub max_character: Option<usize>,
+    /// Fewest general tags to report: when `general` keeps fewer, the N
+    /// highest-scoring ones are taken instead, so a caption is never left
+    /// empty. Excluded and implied tags still stay out, and `max_general`
+    /// still applies. Ignored for [`Threshold::Off`].
+    pub min_general: Option<usize>,
+    /// Temperature the scores are rescaled with, as `sigmoid(logit(p) / t)`,
+    /// before any threshold (fixed, MCut, relative or top-k) sees them.
+    /// Above 1 pulls scores towards 0.5, below 1 pushes them apart; 1 leaves
+    /// them as is.
+    pub temperature: f32,
+    /// Rescale every rating score to sum to 1, after `temperature` and
+    /// before `rating` sees them, turning the independent per-class scores
+    /// into a distribution.
+    pub softmax_ratings: bool,
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        ThresholdConfig {
+            rating: Threshold::Fixed(0.0),
+            general: Threshold::Fixed(0.35),
+            character: Threshold::Fixed(0.85),
+            general_order: TagOrder::Score,
+            max_general: None,
+            max_character: None,
+            min_general: None,
+            temperature: 1.0,
+            softmax_ratings: false,
+        }
+    }
+}
+
+/// Tags to drop from, or restrict, the general and character results.
+///
+/// A tag named in both lists is dropped: `exclude` always wins over `include`.
+#[derive(Debug, Clone, Default)]
+pub struct TagFilter {
+    /// Tags never reported.
+    pub exclude: HashSet<String>,
+    /// If set, the only tags that may be reported.
+    pub include: Option<HashSet<String>>,
+    /// The tags each tag implies, as read by [`load_implications`]. A
+    /// general tag implied by another kept general tag is dropped as
+    /// redundant.
+    pub implications: HashMap<String, HashSet<String>>,
+}
+
+impl TagFilter {
+    fn keeps(&self, name: &str) -> bool {
+        if self.exclude.is_empty() && self.include.is_none() {
+            return true;
+        }
+        let name = canonical_tag(name);
+        !self.exclude.contains(&name)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.contains(&name))
+    }
+
+    /// Drops the tags in `tags` implied by another one of them.
+    fn drop_implied(&self, tags: &mut Vec<(usize, f32)>, names: &[String]) {
+        if self.implications.is_empty() {
+            return;
         }
-        names.push(normalise_tag(tag));
+        let implied: HashSet<&String> = tags
+            .iter()
+            .filter_map(|&(i, _)| self.implications.get(&canonical_tag(&names[i])))
+            .flatten()
+            .collect();
+        tags.retain(|&(i, _)| !implied.contains(&canonical_tag(&names[i])));
+    }
+}
+
+/// Reads tag implications from a CSV file of `tag,implied` rows without a
+/// header, e.g. `blue_eyes,eyes`; lines starting with `#` are skipped.
+///
+/// Names are normalised as for [`load_tag_list`].
+pub fn load_implications(path: &Path) -> Result<HashMap<String, HashSet<String>>> {
+    let csv_err = |source| TaggerError::Csv {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(false)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(csv_err)?;
+    let mut implications: HashMap<String, HashSet<String>> = HashMap::new();
+    for row in rdr.deserialize() {
+        let (tag, implied): (String, String) = row.map_err(csv_err)?;
+        implications
+            .entry(normalise_tag(&tag))
+            .or_default()
+            .insert(normalise_tag(&implied));
     }
-    (names, ratings, general, character)
+    Ok(implications)
+}
+
+/// Reads a newline-separated list of tag names, skipping blank lines.
+///
+/// Names are normalised the same way as the label files, so `long_hair` and
+/// `long hair` both match the `long hair` tag.
+pub fn load_tag_list(path: &Path) -> Result<HashSet<String>> {
+    let text = std::fs::read_to_string(path).map_err(|source| TaggerError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(normalise_tag)
+        .collect())
 }
 
 /// Replaces underscores with spaces unless the name is made entirely of
 /// punctuation/digits (e.g. "1girl", "^_^") — matching WD14 convention.
 fn normalise_tag(name: &str) -> String {
-    if name.chars().all(|c| "_()<>+^.0123456789".contains(c)) {
+    if is_emoticon(name) {
         name.to_string()
     } else {
         name.replace('_', " ")
     }
 }
 
-/// WD14: centre-pad to square → fixed resize → BGR channel order
-/// Output layout: BHWC `[1, H, W, 3]`, raw u8 cast to f32.
-fn prepare_wd14(img: &DynamicImage, size: usize) -> Array4<f32> {
-    let rgba = img.to_rgba8();
-    let (w, h) = rgba.dimensions();
+/// Whether `name` is all punctuation and digits, so its underscores are
+/// part of it.
+fn is_emoticon(name: &str) -> bool {
+    name.chars().all(|c| "_()<>+^.0123456789".contains(c))
+}
+
+/// `(` and `)` preceded by a backslash. Parentheses that are already
+/// escaped are left alone.
+pub fn escape_parens(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    let mut prev = None;
+    for c in name.chars() {
+        if matches!(c, '(' | ')') && prev != Some('\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        prev = Some(c);
+    }
+    escaped
+}
+
+/// `name` as the tag lists hold it, whatever [`TagStyle`] the labels use.
+fn canonical_tag(name: &str) -> String {
+    normalise_tag(&unescape_parens(name))
+}
+
+fn unescape_parens(name: &str) -> String {
+    name.replace("\\(", "(").replace("\\)", ")")
+}
+
+/// Knobs for turning an image into model input. The defaults reproduce the
+/// reference preprocessing of each model.
+#[derive(Debug, Clone, Default)]
+pub struct PreprocessOptions {
+    /// RGB colour of the border added when padding to a square, and of the
+    /// background behind transparent pixels unless `background` is set
+    /// (WD14 only).
+    pub pad_color: [u8; 3],
+    /// RGB colour transparent pixels are composited onto, for both models.
+    /// `None` uses `pad_color` for WD14, and for DINOv3 drops the alpha
+    /// channel, leaving whatever colour the transparent pixels hold.
+    pub background: Option<[u8; 3]>,
+    /// Filter for the resize to the model's input size (WD14 only).
+    pub resize_filter: ResizeFilter,
+    /// Whether to pad or stretch to a square (WD14 only).
+    pub fit: Fit,
+    /// Channel order to feed the model, overriding the model's
+    /// `channel_order` metadata. With neither, BGR (WD14 only).
+    pub channel_order: Option<ChannelOrder>,
+    /// Resample 16-bit and float images at full precision instead of
+    /// reducing them to 8 bits first (WD14 only). Off by default, as the
+    /// models were trained on 8-bit images.
+    pub keep_bit_depth: bool,
+    /// Scaling of the pixel values; none by default, matching the
+    /// reference exports (WD14 only).
+    pub normalize: Normalize,
+}
+
+/// Top-left position of a `w`×`h` image centred on a square canvas whose side
+/// is the longer of the two. When the padding is odd the extra pixel goes to
+/// the right/bottom edge, matching the reference WD14 preprocessing
+/// (`pad_left = (max_dim - w) // 2`).
+fn letterbox_offset(w: u32, h: u32) -> (u32, u32) {
     let m = w.max(h);
-    let mut canvas = RgbaImage::new(m, m);
-    canvas.copy_from(&rgba, (m - w) / 2, (m - h) / 2).unwrap();
-    let resized = image::imageops::resize(
-        &canvas,
-        size as u32,
-        size as u32,
-        image::imageops::FilterType::CatmullRom,
-    );
-    let rgb = DynamicImage::ImageRgba8(resized).to_rgb8();
+    ((m - w) / 2, (m - h) / 2)
+}
+
+/// Where a WD14 export expects the colour channels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    /// `[N, H, W, C]`, as in the SmilingWolf exports.
+    Nhwc,
+    /// `[N, C, H, W]`.
+    Nchw,
+}
+
+/// Reads the layout, square edge length and whether the image is greyscale
+/// from a WD14 input shape, rejecting anything that isn't a square image of
+/// 3 (RGB) or 1 (greyscale) channels. `size` stands in for dynamic
+/// (negative) spatial dimensions, and must equal fixed ones.
+fn wd14_input_layout(shape: &[i64], size: Option<usize>) -> Result<(Layout, usize, bool)> {
+    let (layout, h, w, channels) = match *shape {
+        [_, h, w, c @ (1 | 3)] => (Layout::Nhwc, h, w, c),
+        [_, c @ (1 | 3), h, w] => (Layout::Nchw, h, w, c),
+        [_, _, _, c] if c > 0 => {
+            return Err(TaggerError::UnsupportedModel(format!(
+                "WD14: the input {:?} has {} channels; only 3 (RGB) and 1 (greyscale) \
+                 are supported",
+                shape, c
+            )))
+        }
+        _ => {
+            return Err(TaggerError::UnsupportedModel(format!(
+                "WD14: expected a square [N, H, W, C] or [N, C, H, W] input, got {:?}",
+                shape
+            )))
+        }
+    };
+    let grey = channels == 1;
+    match (h, w) {
+        (h, w) if h > 0 && w > 0 && h == w => match size {
+            Some(size) if size as i64 != h => Err(TaggerError::UnsupportedModel(format!(
+                "WD14: input size {} doesn't fit the model's fixed input {:?}",
+                size, shape
+            ))),
+            _ => Ok((layout, h as usize, grey)),
+        },
+        (h, w) if h > 0 && w > 0 => Err(TaggerError::UnsupportedModel(format!(
+            "WD14: expected a square input, got {:?}",
+            shape
+        ))),
+        // At least one dynamic dimension: a fixed one still has to match.
+        (h, w) => match size {
+            Some(size) if [h, w].iter().all(|&d| d <= 0 || d as usize == size) => {
+                Ok((layout, size, grey))
+            }
+            Some(size) => Err(TaggerError::UnsupportedModel(format!(
+                "WD14: input size {} doesn't fit the model's input {:?}",
+                size, shape
+            ))),
+            None => Err(TaggerError::DynamicInputSize {
+                shape: shape.to_vec(),
+            }),
+        },
+    }
+}
+
+/// Converts any decoded colour type — greyscale, 16-bit or float, with or
+/// without alpha — to 8-bit RGB, compositing transparent pixels over
+/// `background` so they blend into the padding instead of turning into
+/// whatever colour the encoder left under the alpha.
+fn flatten_to_rgb(img: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    // `to_rgba8` replicates grey into all three channels and scales wider
+    // samples down to 0..=255.
+    let rgba = img.to_rgba8();
+    let mut rgb = RgbImage::new(rgba.width(), rgba.height());
+    for (src, dst) in rgba.pixels().zip(rgb.pixels_mut()) {
+        let alpha = u32::from(src[3]);
+        for c in 0..3 {
+            let blended = u32::from(src[c]) * alpha + u32::from(background[c]) * (255 - alpha);
+            dst[c] = ((blended + 127) / 255) as u8;
+        }
+    }
+    rgb
+}
+
+/// Like [`flatten_to_rgb`], but to float RGB in 0.0..=1.0, so 16-bit and
+/// float sources keep their precision.
+fn flatten_to_rgb32f(img: &DynamicImage, background: [u8; 3]) -> Rgb32FImage {
+    let rgba = img.to_rgba32f();
+    let mut rgb = Rgb32FImage::new(rgba.width(), rgba.height());
+    for (src, dst) in rgba.pixels().zip(rgb.pixels_mut()) {
+        let alpha = src[3].clamp(0.0, 1.0);
+        for c in 0..3 {
+            dst[c] = src[c] * alpha + f32::from(background[c]) / 255.0 * (1.0 - alpha);
+        }
+    }
+    rgb
+}
+
+/// WD14: flatten to RGB → centre-pad to square (unless stretching) → fixed
+/// resize → BGR (or RGB, per `channels`) channel order, or with `grey` the
+/// mean of the three channels
+/// Output layout: `[1, H, W, C]` or `[1, C, H, W]` per `layout`, raw u8 cast
+/// to f32, or 0.0..=255.0 at full precision with
+/// [`PreprocessOptions::keep_bit_depth`].
+fn prepare_wd14(
+    img: &DynamicImage,
+    size: usize,
+    layout: Layout,
+    channels: ChannelOrder,
+    grey: bool,
+    options: &PreprocessOptions,
+) -> Array4<f32> {
+    let background = options.background.unwrap_or(options.pad_color);
+    let arr = if options.keep_bit_depth {
+        let pad = options.pad_color.map(|v| f32::from(v) / 255.0);
+        let rgb = flatten_to_rgb32f(img, background);
+        wd14_pixels(rgb, Rgb(pad), size, channels, options, |v| v * 255.0)
+    } else {
+        let rgb = flatten_to_rgb(img, background);
+        wd14_pixels(
+            rgb,
+            Rgb(options.pad_color),
+            size,
+            channels,
+            options,
+            f32::from,
+        )
+    };
+    let arr = if grey {
+        arr.mean_axis(Axis(3))
+            .expect("three channels")
+            .insert_axis(Axis(3))
+    } else {
+        arr
+    };
+    match layout {
+        Layout::Nhwc => arr,
+        Layout::Nchw => arr
+            .permuted_axes([0, 3, 1, 2])
+            .as_standard_layout()
+            .into_owned(),
+    }
+}
+
+/// The squaring, resize, channel ordering and normalisation of
+/// [`prepare_wd14`], for 8-bit or float pixels; `value` maps a sample to
+/// 0.0..=255.0. Returns NHWC.
+fn wd14_pixels<P: Pixel + 'static>(
+    rgb: ImageBuffer<P, Vec<P::Subpixel>>,
+    pad: P,
+    size: usize,
+    channels: ChannelOrder,
+    options: &PreprocessOptions,
+    value: impl Fn(P::Subpixel) -> f32,
+) -> Array4<f32> {
+    let resized = wd14_resized(rgb, pad, size, options);
 
     let mut arr = Array4::<f32>::zeros((1, size, size, 3));
     for y in 0..size {
         for x in 0..size {
             // FIX: original erroneously started at 1, skipping column 0
-            let pixel = rgb.get_pixel(x as u32, y as u32);
+            let pixel = resized.get_pixel(x as u32, y as u32).channels();
+            // The reference WD14 exports were trained on OpenCV-decoded
+            // images, so channel 0 is blue: `[B, G, R]` in 0.0..=255.0.
             for c in 0..3 {
-                arr[(0, y, x, c)] = pixel[2 - c] as f32; // RGB → BGR
+                let rgb_channel = match channels {
+                    ChannelOrder::Bgr => 2 - c, // RGB → BGR
+                    ChannelOrder::Rgb => c,
+                };
+                arr[(0, y, x, c)] = options
+                    .normalize
+                    .apply(value(pixel[rgb_channel]), rgb_channel);
             }
         }
     }
     arr
 }
 
+/// The squaring and resize of [`wd14_pixels`]: the image the model sees.
+fn wd14_resized<P: Pixel + 'static>(
+    rgb: ImageBuffer<P, Vec<P::Subpixel>>,
+    pad: P,
+    size: usize,
+    options: &PreprocessOptions,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let square = match options.fit {
+        Fit::Pad => {
+            let (w, h) = rgb.dimensions();
+            let m = w.max(h);
+            let mut canvas = ImageBuffer::from_pixel(m, m, pad);
+            let (x, y) = letterbox_offset(w, h);
+            canvas
+                .copy_from(&rgb, x, y)
+                .expect("canvas is at least as large as the image");
+            canvas
+        }
+        Fit::Stretch => rgb,
+        Fit::Crop => {
+            let (w, h) = rgb.dimensions();
+            let m = w.min(h);
+            image::imageops::crop_imm(&rgb, (w - m) / 2, (h - m) / 2, m, m).to_image()
+        }
+    };
+    image::imageops::resize(
+        &square,
+        size as u32,
+        size as u32,
+        options.resize_filter.into(),
+    )
+}
+
 /// DINOv3: aspect-preserving resize (long edge ≤ 1024 px), both dims snapped
 /// to multiples of 16, ImageNet-normalised.
 /// Output layout: NCHW `[1, 3, H, W]`, float32.
-fn prepare_dinov3(img: &DynamicImage) -> Array4<f32> {
-    let rgb = img.to_rgb8();
-    let (w, h) = rgb.dimensions();
-
-    let scale = (DINO_MAX_SIZE as f32 / w.max(h) as f32).min(1.0);
-    // Snap a dimension to the nearest multiple of PATCH_SIZE (min 1 patch).
-    let snap = |x: u32| -> u32 {
-        DINO_PATCH_SIZE.max(((x as f32 * scale).round() as u32 / DINO_PATCH_SIZE) * DINO_PATCH_SIZE)
-    };
-    let new_w = snap(w);
-    let new_h = snap(h);
-
-    let resized =
-        image::imageops::resize(&rgb, new_w, new_h, image::imageops::FilterType::Lanczos3);
+fn prepare_dinov3(img: &DynamicImage, background: Option<[u8; 3]>) -> Array4<f32> {
+    let resized = dinov3_resized(img, background);
+    let (new_w, new_h) = resized.dimensions();
 
     let mut arr = Array4::<f32>::zeros((1, 3, new_h as usize, new_w as usize));
     for y in 0..new_h as usize {
@@ -195,82 +1226,646 @@ fn prepare_dinov3(img: &DynamicImage) -> Array4<f32> {
     arr
 }
 
+/// The resize of [`prepare_dinov3`]: the image the model sees.
+fn dinov3_resized(img: &DynamicImage, background: Option<[u8; 3]>) -> RgbImage {
+    let rgb = match background {
+        Some(background) => flatten_to_rgb(img, background),
+        None => img.to_rgb8(),
+    };
+    let (w, h) = rgb.dimensions();
+
+    let scale = (DINO_MAX_SIZE as f32 / w.max(h) as f32).min(1.0);
+    // Snap a dimension to the nearest multiple of PATCH_SIZE (min 1 patch).
+    let snap = |x: u32| -> u32 {
+        DINO_PATCH_SIZE.max(((x as f32 * scale).round() as u32 / DINO_PATCH_SIZE) * DINO_PATCH_SIZE)
+    };
+    image::imageops::resize(
+        &rgb,
+        snap(w),
+        snap(h),
+        image::imageops::FilterType::Lanczos3,
+    )
+}
+
 #[inline]
 fn sigmoid(x: f32) -> f32 {
     1.0 / (1.0 + (-x).exp())
 }
 
+/// Temperature-scales a probability through its logit.
+fn calibrate(p: f32, temperature: f32) -> f32 {
+    // Keep the logit finite for scores of exactly 0 or 1.
+    let p = p.clamp(1e-7, 1.0 - 1e-7);
+    sigmoid((p / (1.0 - p)).ln() / temperature)
+}
+
+/// Maximum Cut Thresholding: the midpoint of the largest gap between
+/// consecutive sorted scores. With fewer than two scores there is no gap, so
+/// this returns 0.0 and leaves the floor to decide.
 fn mcut_threshold(probs: &mut [f32]) -> f32 {
-    probs.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    if probs.len() < 2 {
+        return 0.0;
+    }
+    probs.sort_by(|a, b| b.total_cmp(a));
     probs
         .windows(2)
-        .enumerate()
-        .max_by(|a, b| (a.1[0] - a.1[1]).partial_cmp(&(b.1[0] - b.1[1])).unwrap())
-        .map(|(_t, w)| (w[0] + w[1]) / 2.0)
+        .max_by(|a, b| (a[0] - a[1]).total_cmp(&(b[0] - b[1])))
+        .map(|w| (w[0] + w[1]) / 2.0)
         .unwrap_or(0.0)
 }
 
-/// Shared output-building logic for both backends.
-fn build_output(
-    scores: &[f32],
+fn join_tags(tags: &[(String, f32)]) -> String {
+    tags.iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `scores` as the thresholds see them: rescaled by
+/// [`ThresholdConfig::temperature`], with the `ratings` normalised per
+/// [`ThresholdConfig::softmax_ratings`]. Borrows them when neither applies.
+fn adjusted_scores<'s>(
+    scores: &'s [f32],
+    ratings: &[usize],
+    thresholds: &ThresholdConfig,
+) -> Cow<'s, [f32]> {
+    let mut scores = Cow::Borrowed(scores);
+    if thresholds.temperature != 1.0 {
+        scores = scores
+            .iter()
+            .map(|&p| calibrate(p, thresholds.temperature))
+            .collect();
+    }
+    if thresholds.softmax_ratings {
+        let total: f32 = ratings.iter().map(|&i| scores[i]).sum();
+        if total > 0.0 {
+            let scores = scores.to_mut();
+            for &i in ratings {
+                scores[i] /= total;
+            }
+        }
+    }
+    scores
+}
+
+/// Shared output-building logic for both backends.
+fn build_output(
+    scores: &[f32],
     embedding: Vec<f32>,
-    tag_names: &[String],
-    rating_i: &[usize],
-    general_i: &[usize],
-    character_i: &[usize],
-    g_th: f32,
-    g_mcut: bool,
-    c_th: f32,
-    c_mcut: bool,
-    exclude: &[String],
-) -> OutputData {
-    let rating = rating_i
-        .iter()
-        .map(|&i| (tag_names[i].clone(), scores[i]))
-        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
-        .unwrap_or_else(|| (String::new(), 0.0));
+    labels: &Labels,
+    thresholds: &ThresholdConfig,
+    filter: &TagFilter,
+) -> Prediction {
+    let scores = adjusted_scores(scores, &labels.rating, thresholds);
+    let pick = |indices: &[usize], threshold: Threshold| -> Vec<(usize, f32)> {
+        if threshold == Threshold::Off {
+            return vec![];
+        }
+        indices.iter().map(|&i| (i, scores[i])).collect()
+    };
+    let name = |tags: Vec<(usize, f32)>| -> Vec<(String, f32)> {
+        tags.into_iter()
+            .map(|(i, score)| (labels.names[i].clone(), score))
+            .collect()
+    };
+    let keep = |i: usize| filter.keeps(&labels.names[i]);
 
-    let mut general: Vec<(String, f32)> = general_i
-        .iter()
-        .map(|&i| (tag_names[i].clone(), scores[i]))
-        .collect();
-    let mut character: Vec<(String, f32)> = character_i
-        .iter()
-        .map(|&i| (tag_names[i].clone(), scores[i]))
-        .collect();
+    let mut ratings = pick(&labels.rating, thresholds.rating);
+    let applied_rating = apply_threshold(&mut ratings, thresholds.rating, |_| true);
+    let rating = ratings
+        .first()
+        .map(|&(i, score)| (labels.names[i].clone(), score));
 
-    if g_mcut {
-        let mut gp: Vec<f32> = general.iter().map(|(_, v)| *v).collect();
-        let thresh = mcut_threshold(&mut gp);
-        general.retain(|(_, v)| *v > thresh);
-    } else {
-        general.retain(|(_, v)| *v > g_th);
+    let mut general = pick(&labels.general, thresholds.general);
+    let mut applied_general = apply_threshold(&mut general, thresholds.general, keep);
+    if let Some(min) = thresholds.min_general {
+        if general.len() < min && thresholds.general != Threshold::Off {
+            debug!(
+                "only {} general tags kept, taking the top {}",
+                general.len(),
+                min
+            );
+            general = pick(&labels.general, thresholds.general);
+            applied_general = apply_threshold(&mut general, Threshold::TopK(min), keep);
+        }
     }
-    general.retain(|(name, _)| !exclude.contains(name));
-
-    if c_mcut {
-        let mut cp: Vec<f32> = character.iter().map(|(_, v)| *v).collect();
-        let thresh = mcut_threshold(&mut cp).max(0.15);
-        character.retain(|(_, v)| *v > thresh);
-    } else {
-        character.retain(|(_, v)| *v > c_th);
+    filter.drop_implied(&mut general, &labels.names);
+    let mut character = pick(&labels.character, thresholds.character);
+    let applied_character = apply_threshold(&mut character, thresholds.character, keep);
+    if let Some(max) = thresholds.max_general {
+        general.truncate(max);
     }
-    character.retain(|(name, _)| !exclude.contains(name));
+    if let Some(max) = thresholds.max_character {
+        character.truncate(max);
+    }
+    let applied = AppliedThresholds {
+        rating: applied_rating,
+        general: applied_general,
+        character: applied_character,
+    };
+    debug!("applied thresholds: {:?}", applied);
 
-    general.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    match thresholds.general_order {
+        TagOrder::Score => {}
+        TagOrder::Alpha => general.sort_by(|a, b| labels.names[a.0].cmp(&labels.names[b.0])),
+        TagOrder::None => general.sort_by_key(|&(i, _)| i),
+    }
+    let general = name(general);
+    let general_string = join_tags(&general);
 
-    OutputData {
+    Prediction {
         general,
+        general_string,
         rating,
-        characters: character,
+        ratings: name(ratings),
+        characters: name(character),
         embedding,
+        thresholds: applied,
+        input_size: None,
+    }
+}
+
+/// Keeps the tags (label index and score) selected by `threshold` and
+/// `keep`, highest score first, and returns the score cut-off that was
+/// applied, if there was one. `keep` is applied first, so tags it drops
+/// don't move the cut-off of a relative or MCut threshold, or use up a
+/// top-k slot.
+fn apply_threshold(
+    tags: &mut Vec<(usize, f32)>,
+    threshold: Threshold,
+    keep: impl Fn(usize) -> bool,
+) -> Option<f32> {
+    tags.retain(|&(i, _)| keep(i));
+    let applied = match threshold {
+        Threshold::Fixed(min) => {
+            tags.retain(|(_, v)| *v > min);
+            Some(min)
+        }
+        Threshold::Mcut { floor } => {
+            let mut scores: Vec<f32> = tags.iter().map(|(_, v)| *v).collect();
+            let min = mcut_threshold(&mut scores).max(floor);
+            tags.retain(|(_, v)| *v > min);
+            Some(min)
+        }
+        Threshold::Relative(ratio) => {
+            let top = tags.iter().map(|(_, v)| *v).fold(0.0, f32::max);
+            let min = top * ratio;
+            tags.retain(|(_, v)| *v >= min);
+            Some(min)
+        }
+        Threshold::Off => {
+            tags.clear();
+            None
+        }
+        Threshold::TopK(k) => {
+            sort_by_score(tags);
+            tags.truncate(k);
+            None
+        }
+    };
+    sort_by_score(tags);
+    applied
+}
+
+/// Highest score first. Equal scores fall back to the tag list order, so
+/// ties come out the same on every run whatever order the tags arrive in.
+fn sort_by_score(tags: &mut [(usize, f32)]) {
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+}
+
+/// An ONNX model to build a session from.
+#[derive(Clone, Copy)]
+enum ModelData<'a> {
+    File(&'a Path),
+    Memory(&'a [u8]),
+}
+
+impl std::fmt::Display for ModelData<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelData::File(path) => write!(f, "{}", path.display()),
+            ModelData::Memory(bytes) => write!(f, "<{} bytes in memory>", bytes.len()),
+        }
+    }
+}
+
+/// Names of the external data files the tensors of the ONNX model at `path`
+/// keep their weights in, relative to the model's directory. Large exports
+/// store them beside the graph, e.g. as `model.onnx.data` or
+/// `model.onnx_data`.
+///
+/// Only the protobuf framing down to each initializer's `external_data` is
+/// read; everything else, weights stored in the graph included, is seeked
+/// past, so a multi-gigabyte model isn't read into memory.
+fn external_data_files(path: &Path) -> Result<Vec<String>> {
+    let io_err = |source| TaggerError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = std::fs::File::open(path).map_err(io_err)?;
+    let len = file.metadata().map_err(io_err)?.len();
+    let mut reader = ProtoReader {
+        inner: std::io::BufReader::new(file),
+        pos: 0,
+    };
+    let mut files: Vec<String> = vec![];
+    // ModelProto.graph (7) → GraphProto.initializer (5) →
+    // TensorProto.external_data (13), a StringStringEntryProto.
+    reader
+        .each_field(len, &mut |reader, field, end| {
+            if field != 7 {
+                return Ok(());
+            }
+            reader.each_field(end, &mut |reader, field, end| {
+                if field != 5 {
+                    return Ok(());
+                }
+                reader.each_field(end, &mut |reader, field, end| {
+                    if field != 13 {
+                        return Ok(());
+                    }
+                    if let Some(file) = reader.location(end)? {
+                        if !files.contains(&file) {
+                            files.push(file);
+                        }
+                    }
+                    Ok(())
+                })
+            })
+        })
+        .map_err(io_err)?;
+    Ok(files)
+}
+
+/// Longest external data file name [`external_data_files`] accepts.
+const MAX_LOCATION_LEN: u64 = 4096;
+
+/// Just enough of a protobuf decoder for [`external_data_files`].
+struct ProtoReader<R> {
+    inner: R,
+    /// Bytes consumed so far.
+    pos: u64,
+}
+
+fn malformed(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("malformed ONNX model: {}", what),
+    )
+}
+
+impl<R: std::io::BufRead + std::io::Seek> ProtoReader<R> {
+    fn varint(&mut self) -> std::io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8];
+            self.inner.read_exact(&mut byte)?;
+            self.pos += 1;
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed("varint longer than 10 bytes"))
+    }
+
+    fn skip_to(&mut self, end: u64) -> std::io::Result<()> {
+        let skip = end
+            .checked_sub(self.pos)
+            .and_then(|skip| i64::try_from(skip).ok())
+            .ok_or_else(|| malformed("field overruns its message"))?;
+        self.inner.seek_relative(skip)?;
+        self.pos = end;
+        Ok(())
     }
+
+    /// Calls `visit` with the number and end offset of each
+    /// length-delimited field of the message ending at `end`, skipping
+    /// whatever of it `visit` doesn't read; other fields are skipped
+    /// entirely.
+    fn each_field(
+        &mut self,
+        end: u64,
+        visit: &mut dyn FnMut(&mut Self, u64, u64) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        while self.pos < end {
+            let key = self.varint()?;
+            let len = match key & 7 {
+                0 => {
+                    self.varint()?;
+                    continue;
+                }
+                1 => 8,
+                2 => self.varint()?,
+                5 => 4,
+                wire => return Err(malformed(&format!("unknown wire type {}", wire))),
+            };
+            let field_end = self
+                .pos
+                .checked_add(len)
+                .filter(|&field_end| field_end <= end)
+                .ok_or_else(|| malformed("field overruns its message"))?;
+            if key & 7 == 2 {
+                visit(self, key >> 3, field_end)?;
+            }
+            self.skip_to(field_end)?;
+        }
+        if self.pos != end {
+            return Err(malformed("field overruns its message"));
+        }
+        Ok(())
+    }
+
+    /// The value of a StringStringEntryProto ending at `end` if its key is
+    /// `location`.
+    fn location(&mut self, end: u64) -> std::io::Result<Option<String>> {
+        let (mut key, mut value) = (None, None);
+        self.each_field(end, &mut |reader, field, end| {
+            let len = end - reader.pos;
+            if field > 2 || len > MAX_LOCATION_LEN {
+                return Ok(());
+            }
+            let mut bytes = vec![0u8; len as usize];
+            reader.inner.read_exact(&mut bytes)?;
+            reader.pos = end;
+            let text = String::from_utf8(bytes).map_err(|_| malformed("non-UTF-8 string"))?;
+            if field == 1 {
+                key = Some(text);
+            } else {
+                value = Some(text);
+            }
+            Ok(())
+        })?;
+        Ok(value.filter(|_| key.as_deref() == Some("location")))
+    }
+}
+
+/// Fails if the ONNX model at `path` refers to an external data file that
+/// isn't beside it.
+fn check_external_data(path: &Path) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for file in external_data_files(path)? {
+        if !dir.join(&file).is_file() {
+            return Err(TaggerError::UnsupportedModel(format!(
+                "'{}' keeps its weights in '{}', which is missing; put it in '{}'",
+                path.display(),
+                file,
+                dir.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Names in-memory tag lists in errors.
+const MEMORY_TAGS: &str = "<tags in memory>";
+
+/// Builds an ONNX session for `model` on `device`, with the CPU provider
+/// registered last as the fallback.
+fn build_session(
+    model: ModelData,
+    device: Device,
+    device_id: i32,
+    optimization: Option<OptimizationLevel>,
+) -> Result<Session> {
+    let cpu = ep::CPU::default().build();
+    let providers = match device {
+        Device::Cpu => vec![cpu],
+        Device::Cuda => vec![ep::CUDA::default().with_device_id(device_id).build(), cpu],
+        Device::TensorRt => vec![
+            ep::TensorRT::default().with_device_id(device_id).build(),
+            ep::CUDA::default().with_device_id(device_id).build(),
+            cpu,
+        ],
+        Device::DirectMl => vec![
+            ep::DirectML::default().with_device_id(device_id).build(),
+            cpu,
+        ],
+    };
+    debug!("building session for '{}' on {:?}", model, device);
+    let mut builder = Session::builder()?
+        .with_execution_providers(providers)
+        .map_err(ort::Error::from)?;
+    if let Some(level) = optimization {
+        builder = builder
+            .with_optimization_level(level.into())
+            .map_err(ort::Error::from)?;
+    }
+    let session = match model {
+        ModelData::File(path) => builder.commit_from_file(path)?,
+        ModelData::Memory(bytes) => builder.commit_from_memory(bytes)?,
+    };
+    Ok(session)
+}
+
+/// Runs one DINOv3 input through the tagger and, if loaded, the embedder.
+fn run_dinov3(
+    tagger: &mut Session,
+    embedder: Option<&mut Session>,
+    input: Array4<f32>,
+) -> Result<(Vec<f32>, Vec<f32>)> {
+    // Tag predictions — model outputs raw logits, apply sigmoid here.
+    let tag_out = tagger.run(ort::inputs![Tensor::from_array(input.clone())?])?;
+    let scores: Vec<f32> = first_row(&tag_out[0])?.into_iter().map(sigmoid).collect();
+
+    // Embeddings from the separate embedder session (if loaded).
+    let embedding: Vec<f32> = if let Some(emb_sess) = embedder {
+        let emb_out = emb_sess.run(ort::inputs![Tensor::from_array(input)?])?;
+        emb_out[0]
+            .try_extract_array::<f32>()?
+            .iter()
+            .copied()
+            .collect()
+    } else {
+        vec![]
+    };
+
+    Ok((scores, embedding))
+}
+
+/// Copies the first row of a `[1, N]` output tensor.
+fn first_row(output: &ort::value::DynValue) -> Result<Vec<f32>> {
+    let raw = output.try_extract_array::<f32>()?;
+    Ok(raw.view().into_dimensionality::<Ix2>()?.row(0).to_vec())
+}
+
+/// Copies an output tensor as `f32`, widening it first if the model is fp16.
+fn extract_f32(output: &ort::value::DynValue) -> Result<ndarray::ArrayD<f32>> {
+    match output.dtype() {
+        ort::value::ValueType::Tensor {
+            ty: TensorElementType::Float16,
+            ..
+        } => Ok(output.try_extract_array::<half::f16>()?.mapv(f32::from)),
+        _ => Ok(output.try_extract_array::<f32>()?.to_owned()),
+    }
+}
+
+/// Share of the width and height kept by each crop of [`tta_views`].
+const TTA_CROP: f32 = 0.875;
+
+/// The cheapest views of `img` for test-time augmentation with
+/// [`Predictor::predict_averaged`]: the image itself and its horizontal
+/// mirror.
+pub fn hflip_views(img: &DynamicImage) -> Vec<DynamicImage> {
+    vec![img.clone(), img.fliph()]
+}
+
+/// The [`hflip_views`] of `img`, plus its centre and four corners, each
+/// cropped to 87.5% of the width and height. Every view is then padded and
+/// resized as usual.
+pub fn tta_views(img: &DynamicImage) -> Vec<DynamicImage> {
+    let (w, h) = (img.width(), img.height());
+    let (cw, ch) = (
+        ((w as f32 * TTA_CROP).round() as u32).max(1),
+        ((h as f32 * TTA_CROP).round() as u32).max(1),
+    );
+    let (right, bottom) = (w.saturating_sub(cw), h.saturating_sub(ch));
+    let mut views = hflip_views(img);
+    for (x, y) in [
+        (right / 2, bottom / 2),
+        (0, 0),
+        (right, 0),
+        (0, bottom),
+        (right, bottom),
+    ] {
+        views.push(img.crop_imm(x, y, cw, ch));
+    }
+    views
+}
+
+/// Turns images into input tensors for a loaded model. It only holds
+/// settings, so it can be cloned onto worker threads while the [`Predictor`]
+/// that produced it keeps running inference.
+#[derive(Debug, Clone)]
+pub struct Preprocessor {
+    input: ModelInput,
+    options: PreprocessOptions,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ModelInput {
+    Wd14 {
+        size: usize,
+        layout: Layout,
+        channels: ChannelOrder,
+        grey: bool,
+    },
+    DINOv3,
+}
+
+impl Preprocessor {
+    /// Fails with [`TaggerError::EmptyImage`] if `img` has no pixels, as
+    /// corrupt files sometimes decode to.
+    pub fn prepare(&self, img: &DynamicImage) -> Result<Array4<f32>> {
+        check_not_empty(img)?;
+        Ok(match self.input {
+            ModelInput::Wd14 {
+                size,
+                layout,
+                channels,
+                grey,
+            } => prepare_wd14(img, size, layout, channels, grey, &self.options),
+            ModelInput::DINOv3 => prepare_dinov3(img, self.options.background),
+        })
+    }
+
+    /// The image [`Preprocessor::prepare`] turns into the input tensor:
+    /// flattened, squared and resized, in RGB order and before any
+    /// normalisation, for checking what the model sees. Always 8-bit, even
+    /// with [`PreprocessOptions::keep_bit_depth`].
+    pub fn preview(&self, img: &DynamicImage) -> Result<RgbImage> {
+        check_not_empty(img)?;
+        let options = &self.options;
+        Ok(match self.input {
+            ModelInput::Wd14 { size, grey, .. } => {
+                let background = options.background.unwrap_or(options.pad_color);
+                let rgb = flatten_to_rgb(img, background);
+                let mut resized = wd14_resized(rgb, Rgb(options.pad_color), size, options);
+                if grey {
+                    for pixel in resized.pixels_mut() {
+                        let [r, g, b] = pixel.0.map(u16::from);
+                        let mean = ((r + g + b + 1) / 3) as u8;
+                        *pixel = Rgb([mean; 3]);
+                    }
+                }
+                resized
+            }
+            ModelInput::DINOv3 => dinov3_resized(img, options.background),
+        })
+    }
+}
+
+/// Fails with [`TaggerError::EmptyImage`] if `img` has no pixels.
+fn check_not_empty(img: &DynamicImage) -> Result<()> {
+    if img.width() == 0 || img.height() == 0 {
+        return Err(TaggerError::EmptyImage {
+            width: img.width(),
+            height: img.height(),
+        });
+    }
+    Ok(())
+}
+
+/// What [`Predictor::load`] loaded, for checking a setup without tagging.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    /// ONNX sessions loaded: the number of ensembled WD14 models, or for
+    /// DINOv3 the tagger plus the embedder if it was found.
+    pub sessions: usize,
+    /// Name and shape of each input of the (first) tagger model, with -1
+    /// for dynamic dimensions.
+    pub inputs: Vec<(String, Vec<i64>)>,
+    /// Name and shape of each output of the (first) tagger model.
+    pub outputs: Vec<(String, Vec<i64>)>,
+    /// Square input edge length in pixels (WD14 only; DINOv3 takes
+    /// variable sizes).
+    pub input_size: Option<usize>,
+    /// Channel order images are fed in (WD14 only).
+    pub channel_order: Option<ChannelOrder>,
+    /// Whether the model takes a single greyscale channel, the mean of
+    /// the RGB ones (WD14 only).
+    pub greyscale: bool,
+}
+
+/// Name and tensor shape of each of `outlets`.
+fn outlet_shapes(outlets: &[ort::value::Outlet]) -> Vec<(String, Vec<i64>)> {
+    outlets
+        .iter()
+        .map(|outlet| {
+            let shape = match outlet.dtype() {
+                ort::value::ValueType::Tensor { shape, .. } => shape.to_vec(),
+                _ => vec![],
+            };
+            (outlet.name().to_string(), shape)
+        })
+        .collect()
+}
+
+/// One WD14 model: the input its images are fed through, and the positions
+/// of the outputs holding the tag scores and (if any) the embedding.
+struct Wd14Session {
+    session: Session,
+    input: String,
+    scores: usize,
+    embedding: Option<usize>,
+    /// Whether the image input is `float16` rather than `float32`.
+    half: bool,
+}
+
+/// Which model output holds the tag scores.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputSelector {
+    Index(usize),
+    Name(String),
 }
 
 enum LoadedModel {
     Wd14 {
-        session: Session,
-        size: usize, // model's expected square input edge, e.g. 448
+        sessions: Vec<Wd14Session>, // more than one when ensembling
+        size: usize,                // model's expected square input edge, e.g. 448
+        layout: Layout,
+        channels: ChannelOrder,
+        grey: bool, // one luminance channel instead of three
     },
     DINOv3 {
         tagger: Session,
@@ -278,69 +1873,549 @@ enum LoadedModel {
     },
 }
 
+/// Model and labels supplied to a [`Predictor`] instead of fetched from the
+/// hub.
+enum LocalSource {
+    Files { model: PathBuf, tags: PathBuf },
+    Memory { model: Vec<u8>, tags: Vec<u8> },
+}
+
+/// Lazily-loaded tagger session plus its tag vocabulary.
+///
+/// Models are downloaded from the Hugging Face hub on first use; call
+/// [`Predictor::load`] up front to pay that cost eagerly.
 pub struct Predictor {
     kind: ModelKind,
+    wd14_models: Vec<String>,
+    #[cfg_attr(not(feature = "hub"), allow(dead_code))]
+    offline: bool,
+    #[cfg_attr(not(feature = "hub"), allow(dead_code))]
+    max_retries: u32,
+    #[cfg_attr(not(feature = "hub"), allow(dead_code))]
+    revision: Option<String>,
+    device: Device,
+    device_id: i32,
+    optimization: Option<OptimizationLevel>,
+    warmup: bool,
+    preprocess: PreprocessOptions,
+    tag_style: TagStyle,
+    categories: CategoryMap,
+    local: Option<LocalSource>,
+    input_name: Option<String>,
+    output: Option<OutputSelector>,
+    input_size: Option<usize>,
     model: Option<LoadedModel>,
-    tag_names: Vec<String>,
-    rating_i: Vec<usize>,
-    general_i: Vec<usize>,
-    character_i: Vec<usize>,
+    labels: Labels,
+    /// Built on first use and shared by label and model loading.
+    #[cfg(feature = "hub")]
+    hub: OnceLock<Hub>,
 }
 
 impl Predictor {
     pub fn new(kind: ModelKind) -> Self {
         Predictor {
             kind,
+            wd14_models: vec![WD14_DEFAULT_MODEL.to_string()],
+            offline: false,
+            max_retries: 2,
+            revision: None,
+            device: Device::Cuda,
+            device_id: 0,
+            optimization: None,
+            warmup: false,
+            preprocess: PreprocessOptions::default(),
+            tag_style: TagStyle::default(),
+            categories: CategoryMap::default(),
+            local: None,
+            input_name: None,
+            output: None,
+            input_size: None,
             model: None,
-            tag_names: vec![],
-            rating_i: vec![],
-            general_i: vec![],
-            character_i: vec![],
+            labels: Labels::default(),
+            #[cfg(feature = "hub")]
+            hub: OnceLock::new(),
+        }
+    }
+
+    /// Selects which WD14 tagger to use, e.g. `SmilingWolf/wd-swinv2-tagger-v3`.
+    /// Ignored by the DINOv3 backends.
+    pub fn with_wd14_model(mut self, name: impl Into<String>) -> Self {
+        self.wd14_models = vec![name.into()];
+        self
+    }
+
+    /// Ensembles several WD14 taggers, averaging their tag probabilities.
+    /// They must share a tag list and input size; embeddings come from the
+    /// first. An empty list keeps the current selection.
+    pub fn with_wd14_models<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let names: Vec<String> = names.into_iter().map(Into::into).collect();
+        if !names.is_empty() {
+            self.wd14_models = names;
+        }
+        self
+    }
+
+    /// Only use files already in the local Hugging Face cache; loading fails
+    /// instead of downloading when one is missing.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// How many times to retry a download that failed transiently (2 by
+    /// default), with exponential backoff starting at one second. A 4xx
+    /// response other than 429 fails at once.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Downloads the model (and, for WD14, its tag list) at `revision` of
+    /// its hub repo, a branch, tag or commit hash, instead of the latest
+    /// `main`. The DINOv3 vocabulary lives in another repo and is always
+    /// fetched from its `main`.
+    pub fn with_revision(mut self, revision: impl Into<String>) -> Self {
+        self.revision = Some(revision.into());
+        self
+    }
+
+    /// Runs inference on `device` (CUDA device 0 by default). `device_id`
+    /// selects the GPU for every provider except `Cpu`.
+    pub fn with_device(mut self, device: Device, device_id: i32) -> Self {
+        self.device = device;
+        self.device_id = device_id;
+        self
+    }
+
+    /// Builds the sessions with graph optimisation `level` instead of ONNX
+    /// Runtime's default (all optimisations).
+    pub fn with_optimization_level(mut self, level: OptimizationLevel) -> Self {
+        self.optimization = Some(level);
+        self
+    }
+
+    /// Runs one untimed inference when the session is built, so the latency
+    /// of the first real one is like that of the rest.
+    pub fn with_warmup(mut self, warmup: bool) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    pub fn with_preprocess_options(mut self, options: PreprocessOptions) -> Self {
+        self.preprocess = options;
+        self
+    }
+
+    /// Writes tag names in `style` ([`TagStyle::Spaces`] by default).
+    pub fn with_tag_style(mut self, style: TagStyle) -> Self {
+        self.tag_style = style;
+        self
+    }
+
+    /// Buckets tags by `categories` instead of WD14's 9/0/4 category codes.
+    pub fn with_category_map(mut self, categories: CategoryMap) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    /// Reports tag names with their original underscores (`long_hair`)
+    /// rather than spaces (`long hair`), as some training tools expect.
+    /// Shorthand for [`TagStyle::Keep`].
+    pub fn with_underscores(self, underscores: bool) -> Self {
+        if underscores {
+            self.with_tag_style(TagStyle::Keep)
+        } else {
+            self
+        }
+    }
+
+    /// Loads the model and its labels from local files instead of the hub:
+    /// an ONNX model plus a WD14 tag CSV, or for DINOv3 a vocabulary JSON.
+    /// DINOv3 then runs without embeddings.
+    pub fn with_local_files(mut self, model: impl Into<PathBuf>, tags: impl Into<PathBuf>) -> Self {
+        self.local = Some(LocalSource::Files {
+            model: model.into(),
+            tags: tags.into(),
+        });
+        self
+    }
+
+    /// Like [`Predictor::with_local_files`], with the file contents already
+    /// in memory, e.g. a model bundled into the application.
+    pub fn with_model_bytes(mut self, model: impl Into<Vec<u8>>, tags: impl Into<Vec<u8>>) -> Self {
+        self.local = Some(LocalSource::Memory {
+            model: model.into(),
+            tags: tags.into(),
+        });
+        self
+    }
+
+    /// Feeds images to the WD14 input named `name` rather than the first
+    /// input, for exports that name it differently or take extra inputs.
+    /// Loading fails if a model has no such input.
+    pub fn with_input_name(mut self, name: impl Into<String>) -> Self {
+        self.input_name = Some(name.into());
+        self
+    }
+
+    /// Reads the WD14 tag scores from the given output rather than the
+    /// first, for models with several heads. The embedding then comes from
+    /// the first other output, if there is one.
+    pub fn with_output(mut self, output: OutputSelector) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Feeds WD14 models whose input has a dynamic image size `size`×`size`
+    /// images. Models with a fixed size must match it.
+    pub fn with_input_size(mut self, size: usize) -> Self {
+        self.input_size = Some(size);
+        self
+    }
+
+    /// Downloads the model and labels and builds the ONNX session(s), as
+    /// [`Predictor::load_labels`] then [`Predictor::load_session`].
+    /// A no-op once the predictor is loaded.
+    pub fn load(&mut self) -> Result<()> {
+        self.load_labels()?;
+        self.load_session()
+    }
+
+    /// Downloads and parses just the tag list, so [`Predictor::labels`] can
+    /// be inspected without building an ONNX session. A no-op once the
+    /// labels are loaded.
+    pub fn load_labels(&mut self) -> Result<()> {
+        if !self.labels.names.is_empty() {
+            return Ok(());
         }
+        let style = self.tag_style;
+        let categories = &self.categories;
+        self.labels = match (&self.local, self.kind) {
+            (Some(LocalSource::Files { tags, .. }), ModelKind::Wd14) => {
+                load_wd14_labels(tags, style, categories)?
+            }
+            (Some(LocalSource::Files { tags, .. }), ModelKind::DINOv3 | ModelKind::DINOv3Q8) => {
+                load_dino_labels(tags, style, categories)?
+            }
+            (Some(LocalSource::Memory { tags, .. }), ModelKind::Wd14) => {
+                let rdr = ReaderBuilder::new()
+                    .has_headers(true)
+                    .from_reader(tags.as_slice());
+                parse_wd14_labels(
+                    rdr,
+                    tags.len() as u64,
+                    Path::new(MEMORY_TAGS),
+                    style,
+                    categories,
+                )?
+            }
+            (Some(LocalSource::Memory { tags, .. }), ModelKind::DINOv3 | ModelKind::DINOv3Q8) => {
+                parse_dino_labels(tags, Path::new(MEMORY_TAGS), style, categories)?
+            }
+            (None, ModelKind::Wd14) => self.hub_wd14_labels()?,
+            (None, ModelKind::DINOv3 | ModelKind::DINOv3Q8) => self.hub_dino_labels()?,
+        };
+        Ok(())
     }
 
-    fn ensure_loaded(&mut self) {
+    /// Downloads the model and builds the ONNX session(s), loading the
+    /// labels first if needed since the model is checked against them.
+    /// A no-op once the session is built.
+    pub fn load_session(&mut self) -> Result<()> {
         if self.model.is_some() {
-            return;
+            return Ok(());
+        }
+        self.load_labels()?;
+        let local = match &self.local {
+            Some(LocalSource::Files { model, .. }) => {
+                check_external_data(model)?;
+                Some(ModelData::File(model))
+            }
+            Some(LocalSource::Memory { model, .. }) => Some(ModelData::Memory(model)),
+            None => None,
+        };
+        let model = match (local, self.kind) {
+            (Some(model), ModelKind::Wd14) => self.load_wd14_from(&[model])?,
+            (Some(model), ModelKind::DINOv3 | ModelKind::DINOv3Q8) => {
+                self.load_dinov3_from(model, None)?
+            }
+            (None, _) => self.load_hub_model()?,
+        };
+        self.model = Some(model);
+        if self.warmup {
+            self.warm_up()?;
         }
+        Ok(())
+    }
+
+    /// Runs one inference on a blank image, so the first real one doesn't
+    /// pay for lazy allocation and kernel selection.
+    fn warm_up(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let input = self
+            .preprocessor()?
+            .prepare(&DynamicImage::new_rgb8(448, 448))?;
+        self.run(input)?;
+        info!("warm-up inference took {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// The hub client, built the first time it is needed.
+    #[cfg(feature = "hub")]
+    fn hub(&self) -> Result<&Hub> {
+        if let Some(hub) = self.hub.get() {
+            return Ok(hub);
+        }
+        let hub = Hub::new(self.offline, self.max_retries)?;
+        Ok(self.hub.get_or_init(|| hub))
+    }
+
+    #[cfg(feature = "hub")]
+    fn load_hub_model(&self) -> Result<LoadedModel> {
+        let hub = self.hub()?;
         match self.kind {
-            ModelKind::Wd14 => self.load_wd14(),
-            ModelKind::DINOv3 | ModelKind::DINOv3Q8 => self.load_dinov3(),
+            ModelKind::Wd14 => self.load_wd14(hub),
+            ModelKind::DINOv3 | ModelKind::DINOv3Q8 => self.load_dinov3(hub),
         }
     }
 
-    fn load_wd14(&mut self) {
-        let api = Api::new().unwrap();
-        let repo = api.model(WD14_REPO.to_string());
-        let model_path = repo.get(WD14_MODEL_FILE).unwrap();
+    #[cfg(not(feature = "hub"))]
+    fn load_hub_model(&self) -> Result<LoadedModel> {
+        Err(TaggerError::HubDisabled)
+    }
 
-        let (tags, r, g, c) = load_wd14_labels(WD14_REPO, WD14_TAG_CSV);
-        self.tag_names = tags;
-        self.rating_i = r;
-        self.general_i = g;
-        self.character_i = c;
+    #[cfg(feature = "hub")]
+    fn hub_dino_labels(&self) -> Result<Labels> {
+        let hub = self.hub()?;
+        load_dino_labels(
+            &hub.get(DINO_BASE_REPO, None, DINO_VOCAB_FILE)?,
+            self.tag_style,
+            &self.categories,
+        )
+    }
 
-        let session = Session::builder()
-            .unwrap()
-            .with_execution_providers([ep::CUDA::default()
-                .with_device_id(0)
-                .build()])
-            .unwrap()
-            .commit_from_file(model_path)
-            .unwrap();
+    #[cfg(not(feature = "hub"))]
+    fn hub_dino_labels(&self) -> Result<Labels> {
+        Err(TaggerError::HubDisabled)
+    }
 
-        let size = match session.inputs()[0].dtype() {
-            ort::value::ValueType::Tensor { shape, .. } => shape[2] as usize,
-            _ => panic!("WD14: expected a tensor input"),
-        };
+    /// The tag list shared by every selected WD14 model: the one built into
+    /// the binary if they are all the model it was built for, else fetched.
+    fn hub_wd14_labels(&self) -> Result<Labels> {
+        #[cfg(feature = "embedded-tags")]
+        if self
+            .wd14_models
+            .iter()
+            .all(|name| name == EMBEDDED_WD14_MODEL)
+        {
+            return embedded_wd14_labels(self.tag_style, &self.categories);
+        }
+        self.fetch_wd14_labels()
+    }
+
+    #[cfg(not(feature = "hub"))]
+    fn fetch_wd14_labels(&self) -> Result<Labels> {
+        Err(TaggerError::HubDisabled)
+    }
+
+    #[cfg(feature = "hub")]
+    fn fetch_wd14_labels(&self) -> Result<Labels> {
+        let hub = self.hub()?;
+        let mut labels: Option<Labels> = None;
+        for name in &self.wd14_models {
+            let tag_file = format!("{}/{}", name, WD14_TAG_CSV);
+            let model_labels = load_wd14_labels(
+                &hub.get(WD14_REPO, self.revision.as_deref(), &tag_file)?,
+                self.tag_style,
+                &self.categories,
+            )?;
+            match &labels {
+                None => labels = Some(model_labels),
+                Some(first) if first.names != model_labels.names => {
+                    return Err(TaggerError::UnsupportedModel(format!(
+                        "WD14 ensemble: '{}' has a different tag list from '{}'",
+                        name, self.wd14_models[0]
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(labels.expect("at least one WD14 model is selected"))
+    }
 
-        self.model = Some(LoadedModel::Wd14 { session, size });
+    #[cfg(feature = "hub")]
+    fn load_wd14(&self, hub: &Hub) -> Result<LoadedModel> {
+        let mut model_paths = vec![];
+        let revision = self.revision.as_deref();
+        for name in &self.wd14_models {
+            let model_file = format!("{}/{}", name, WD14_MODEL_FILE);
+            let path = hub.get(WD14_REPO, revision, &model_file)?;
+            // Fetched into the same snapshot directory, so ONNX Runtime
+            // finds them beside the graph.
+            for data in external_data_files(&path)? {
+                debug!("fetching external data '{}' of '{}'", data, model_file);
+                hub.get(WD14_REPO, revision, &format!("{}/{}", name, data))?;
+            }
+            model_paths.push(path);
+        }
+        let models: Vec<ModelData> = model_paths.iter().map(|p| ModelData::File(p)).collect();
+        self.load_wd14_from(&models)
     }
 
-    fn load_dinov3(&mut self) {
-        let api = Api::new().unwrap();
-        let onnx_repo = api.model(DINO_ONNX_REPO.to_string());
+    fn load_wd14_from(&self, models: &[ModelData]) -> Result<LoadedModel> {
+        let labels = &self.labels;
+        let mut sessions = vec![];
+        let mut input = None;
+        for &model_path in models {
+            info!(
+                "loading WD14 model '{}' ({} tags)",
+                model_path,
+                labels.names.len()
+            );
+            let session =
+                build_session(model_path, self.device, self.device_id, self.optimization)?;
+
+            let image_input = match &self.input_name {
+                None => &session.inputs()[0],
+                Some(name) => session
+                    .inputs()
+                    .iter()
+                    .find(|input| input.name() == name)
+                    .ok_or_else(|| {
+                        let names: Vec<&str> =
+                            session.inputs().iter().map(|input| input.name()).collect();
+                        TaggerError::UnsupportedModel(format!(
+                            "WD14: '{}' has no input named '{}' (inputs: {})",
+                            model_path,
+                            name,
+                            names.join(", ")
+                        ))
+                    })?,
+            };
+            let input_name = image_input.name().to_string();
+            let (layout, size, grey, half) = match image_input.dtype() {
+                ort::value::ValueType::Tensor { ty, shape, .. } => {
+                    debug!("WD14 input shape: {:?} ({})", shape, ty);
+                    let half = match ty {
+                        TensorElementType::Float32 => false,
+                        TensorElementType::Float16 => true,
+                        ty => {
+                            return Err(TaggerError::UnsupportedModel(format!(
+                                "WD14: input '{}' of '{}' is {}; only float32 and float16 \
+                                 inputs are supported",
+                                input_name, model_path, ty
+                            )))
+                        }
+                    };
+                    let (layout, size, grey) = wd14_input_layout(shape, self.input_size)?;
+                    // The override only takes the place of dynamic dimensions.
+                    let overridden =
+                        self.input_size.is_some() && shape.iter().skip(1).any(|&d| d <= 0);
+                    info!(
+                        "WD14 input size: {}x{}{}",
+                        size,
+                        size,
+                        if overridden { " (overridden)" } else { "" }
+                    );
+                    if grey {
+                        info!("WD14 input is greyscale");
+                    }
+                    (layout, size, grey, half)
+                }
+                _ => {
+                    return Err(TaggerError::UnsupportedModel(
+                        "WD14: expected a tensor input".to_string(),
+                    ))
+                }
+            };
+            let channels = match self.preprocess.channel_order {
+                Some(channels) => channels,
+                None => session
+                    .metadata()
+                    .ok()
+                    .and_then(|metadata| metadata.custom("channel_order"))
+                    .and_then(|value| ChannelOrder::from_metadata(&value))
+                    .unwrap_or_default(),
+            };
+            debug!("WD14 channel order: {:?}", channels);
+            let model_input = (layout, size, channels, grey);
+            let outputs = session.outputs();
+            let scores = match &self.output {
+                None => 0,
+                Some(OutputSelector::Index(i)) if *i < outputs.len() => *i,
+                Some(OutputSelector::Name(name)) => outputs
+                    .iter()
+                    .position(|output| output.name() == name)
+                    .ok_or_else(|| {
+                        let names: Vec<&str> = outputs.iter().map(|output| output.name()).collect();
+                        TaggerError::UnsupportedModel(format!(
+                            "WD14: '{}' has no output named '{}' (outputs: {})",
+                            model_path,
+                            name,
+                            names.join(", ")
+                        ))
+                    })?,
+                Some(OutputSelector::Index(i)) => {
+                    return Err(TaggerError::UnsupportedModel(format!(
+                        "WD14: output {} requested but '{}' has {}",
+                        i,
+                        model_path,
+                        outputs.len()
+                    )))
+                }
+            };
+            let embedding = (0..outputs.len()).find(|&i| i != scores);
+            debug!(
+                "WD14 scores output: {} (embedding: {:?})",
+                outputs[scores].name(),
+                embedding.map(|i| outputs[i].name())
+            );
+            if let ort::value::ValueType::Tensor { shape, .. } = outputs[scores].dtype() {
+                let tags = shape.last().copied().unwrap_or(-1);
+                if tags >= 0 && tags as usize != labels.names.len() {
+                    return Err(TaggerError::UnsupportedModel(format!(
+                        "WD14: '{}' scores {} tags but its tag list has {}",
+                        model_path,
+                        tags,
+                        labels.names.len()
+                    )));
+                }
+            }
+            match input {
+                None => input = Some(model_input),
+                Some(first) if first != model_input => {
+                    return Err(TaggerError::UnsupportedModel(format!(
+                        "WD14 ensemble: '{}' takes a different input from the first model",
+                        model_path
+                    )))
+                }
+                Some(_) => {}
+            }
+            sessions.push(Wd14Session {
+                session,
+                input: input_name,
+                scores,
+                embedding,
+                half,
+            });
+        }
 
+        let (layout, size, channels, grey) = input.expect("at least one WD14 model is selected");
+        Ok(LoadedModel::Wd14 {
+            sessions,
+            size,
+            layout,
+            channels,
+            grey,
+        })
+    }
+
+    #[cfg(feature = "hub")]
+    fn load_dinov3(&self, hub: &Hub) -> Result<LoadedModel> {
         let quant = self.kind == ModelKind::DINOv3Q8;
 
         let (tagger_model, embed_model): (&str, &str) = if quant {
@@ -349,130 +2424,626 @@ impl Predictor {
             (DINO_TAGGER_MODEL, DINO_EMBED_MODEL)
         };
 
-        let tagger_path = onnx_repo.get(tagger_model).unwrap();
+        let tagger_path = hub.get(DINO_ONNX_REPO, self.revision.as_deref(), tagger_model)?;
         if !quant {
-            let _ = onnx_repo.get(DINO_TAGGER_DATA).unwrap();
-        }
-
-        let (tags, r, g, c) = load_dino_labels(DINO_BASE_REPO, DINO_VOCAB_FILE);
-        self.tag_names = tags;
-        self.rating_i = r;
-        self.general_i = g;
-        self.character_i = c;
-
-        let tagger = Session::builder()
-            .unwrap()
-            .with_execution_providers([ep::CUDA::default()
-                .with_device_id(0)
-                .build()])
-            .unwrap()
-            .commit_from_file(tagger_path)
-            .unwrap();
+            hub.get(DINO_ONNX_REPO, self.revision.as_deref(), DINO_TAGGER_DATA)?;
+        }
 
-        let embedder = onnx_repo.get(embed_model).ok().map(|emb_path| {
-            if !quant {
-                let _ = onnx_repo.get(DINO_EMBED_DATA).ok();
+        // The embedder is optional: a failed download just disables embeddings.
+        let embed_path = match hub.get(DINO_ONNX_REPO, self.revision.as_deref(), embed_model) {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("DINOv3 embeddings disabled: {}", e);
+                None
             }
-            Session::builder()
-                .unwrap()
-                .with_execution_providers([ep::CUDA::default()
-                    .with_device_id(0)
-                    .build()])
-                .unwrap()
-                .commit_from_file(emb_path)
-                .unwrap()
-        });
+        };
+        if embed_path.is_some() && !quant {
+            let _ = hub.get(DINO_ONNX_REPO, self.revision.as_deref(), DINO_EMBED_DATA);
+        }
+
+        self.load_dinov3_from(
+            ModelData::File(&tagger_path),
+            embed_path.as_deref().map(ModelData::File),
+        )
+    }
+
+    fn load_dinov3_from(
+        &self,
+        tagger: ModelData,
+        embedder: Option<ModelData>,
+    ) -> Result<LoadedModel> {
+        info!(
+            "loading DINOv3 tagger '{}' ({} tags)",
+            tagger,
+            self.labels.names.len()
+        );
 
-        self.model = Some(LoadedModel::DINOv3 { tagger, embedder });
+        let tagger = build_session(tagger, self.device, self.device_id, self.optimization)?;
+        let embedder = match embedder {
+            Some(model) => Some(build_session(
+                model,
+                self.device,
+                self.device_id,
+                self.optimization,
+            )?),
+            None => None,
+        };
+
+        Ok(LoadedModel::DINOv3 { tagger, embedder })
     }
 
+    /// Tag names and categories of the loaded model; empty before
+    /// [`Predictor::load`].
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    /// Describes the loaded model's tensors and input handling.
+    pub fn model_info(&self) -> Result<ModelInfo> {
+        Ok(match self.model.as_ref().ok_or(TaggerError::NotLoaded)? {
+            LoadedModel::Wd14 {
+                sessions,
+                size,
+                channels,
+                grey,
+                ..
+            } => ModelInfo {
+                sessions: sessions.len(),
+                inputs: outlet_shapes(sessions[0].session.inputs()),
+                outputs: outlet_shapes(sessions[0].session.outputs()),
+                input_size: Some(*size),
+                channel_order: Some(*channels),
+                greyscale: *grey,
+            },
+            LoadedModel::DINOv3 { tagger, embedder } => ModelInfo {
+                sessions: 1 + embedder.is_some() as usize,
+                inputs: outlet_shapes(tagger.inputs()),
+                outputs: outlet_shapes(tagger.outputs()),
+                input_size: None,
+                channel_order: None,
+                greyscale: false,
+            },
+        })
+    }
+
+    /// Returns the preprocessing matching the loaded model.
+    pub fn preprocessor(&self) -> Result<Preprocessor> {
+        let input = match self.model.as_ref().ok_or(TaggerError::NotLoaded)? {
+            LoadedModel::Wd14 {
+                size,
+                layout,
+                channels,
+                grey,
+                ..
+            } => ModelInput::Wd14 {
+                size: *size,
+                layout: *layout,
+                channels: *channels,
+                grey: *grey,
+            },
+            LoadedModel::DINOv3 { .. } => ModelInput::DINOv3,
+        };
+        Ok(Preprocessor {
+            input,
+            options: self.preprocess.clone(),
+        })
+    }
+
+    /// Tags `img`, loading the model first if needed.
+    ///
+    /// Each category keeps the tags chosen by its entry in `thresholds`;
+    /// `filter` then applies to the general and character lists.
     pub fn predict(
         &mut self,
         img: &DynamicImage,
-        g_th: f32,
-        g_mcut: bool,
-        c_th: f32,
-        c_mcut: bool,
-        exclude: &[String],
-    ) -> OutputData {
-        self.ensure_loaded();
-
-        // Produce (probabilities, embedding) — both owned Vecs — before
-        // touching any other field of self.
-        let (scores, embedding): (Vec<f32>, Vec<f32>) = match self.model.as_mut().unwrap() {
-            LoadedModel::Wd14 { session, size } => {
-                let arr = prepare_wd14(img, *size);
-                let outputs = session
-                    .run(ort::inputs![Tensor::from_array(arr).unwrap()])
-                    .unwrap();
-
-                let pred_raw = outputs[0].try_extract_array::<f32>().unwrap();
-                let scores: Vec<f32> = pred_raw
-                    .view()
-                    .into_dimensionality::<Ix2>()
-                    .unwrap()
-                    .row(0)
-                    .to_vec();
-
-                let emb_raw: Vec<f32> = outputs[1]
-                    .try_extract_array::<f32>()
-                    .unwrap()
-                    .iter()
-                    .copied()
-                    .collect();
+        thresholds: &ThresholdConfig,
+        filter: &TagFilter,
+    ) -> Result<Prediction> {
+        self.load()?;
+        let input = self.preprocessor()?.prepare(img)?;
+        self.predict_prepared(input, thresholds, filter)
+    }
 
-                (scores, emb_raw)
-            }
+    /// Like [`Predictor::predict`], for an encoded image (PNG, JPEG, ...)
+    /// such as an upload or a database blob.
+    pub fn predict_bytes(
+        &mut self,
+        bytes: &[u8],
+        thresholds: &ThresholdConfig,
+        filter: &TagFilter,
+    ) -> Result<Prediction> {
+        let img = image::load_from_memory(bytes).map_err(TaggerError::Decode)?;
+        self.predict(&img, thresholds, filter)
+    }
 
-            LoadedModel::DINOv3 { tagger, embedder } => {
-                let arr = prepare_dinov3(img);
-
-                // Tag predictions — model outputs raw logits, apply sigmoid here.
-                let tag_out = tagger
-                    .run(ort::inputs![Tensor::from_array(arr.clone()).unwrap()])
-                    .unwrap();
-                let logit_raw = tag_out[0].try_extract_array::<f32>().unwrap();
-                let scores: Vec<f32> = logit_raw
-                    .view()
-                    .into_dimensionality::<Ix2>()
-                    .unwrap()
-                    .row(0)
-                    .iter()
-                    .map(|&x| sigmoid(x))
-                    .collect();
-
-                // Embeddings from the separate embedder session (if loaded).
-                let embedding: Vec<f32> = if let Some(emb_sess) = embedder {
-                    let emb_out = emb_sess
-                        .run(ort::inputs![Tensor::from_array(arr).unwrap()])
-                        .unwrap();
-                    emb_out[0]
-                        .try_extract_array::<f32>()
-                        .unwrap()
-                        .iter()
-                        .copied()
-                        .collect()
-                } else {
-                    vec![]
-                };
+    /// Like [`Predictor::predict`], for an input already produced by this
+    /// predictor's [`Preprocessor`].
+    pub fn predict_prepared(
+        &mut self,
+        input: Array4<f32>,
+        thresholds: &ThresholdConfig,
+        filter: &TagFilter,
+    ) -> Result<Prediction> {
+        let input_size = self.input_dimensions(&input);
+        let (scores, embedding) = self.run(input)?;
+        let mut prediction = build_output(&scores, embedding, &self.labels, thresholds, filter);
+        prediction.input_size = input_size;
+        Ok(prediction)
+    }
+
+    /// Like [`Predictor::predict_prepared`] for several inputs at once,
+    /// returning one prediction per input in order. On WD14 this runs a
+    /// single batched inference, which is much faster than one call per image.
+    pub fn predict_batch_prepared(
+        &mut self,
+        inputs: Vec<Array4<f32>>,
+        thresholds: &ThresholdConfig,
+        filter: &TagFilter,
+    ) -> Result<Vec<Prediction>> {
+        let sizes: Vec<_> = inputs
+            .iter()
+            .map(|input| self.input_dimensions(input))
+            .collect();
+        let outputs = self.run_batch(inputs)?;
+        Ok(outputs
+            .into_iter()
+            .zip(sizes)
+            .map(|((scores, embedding), input_size)| {
+                let mut prediction =
+                    build_output(&scores, embedding, &self.labels, thresholds, filter);
+                prediction.input_size = input_size;
+                prediction
+            })
+            .collect())
+    }
 
-                (scores, embedding)
+    /// Like [`Predictor::predict`] for several views of one image, such as
+    /// its [`tta_views`]: they run through the model as one batch and their
+    /// scores are averaged before thresholding. The embedding is that of the
+    /// first view. Fails with [`TaggerError::NoViews`] if `views` is empty.
+    pub fn predict_averaged(
+        &mut self,
+        views: &[DynamicImage],
+        thresholds: &ThresholdConfig,
+        filter: &TagFilter,
+    ) -> Result<Prediction> {
+        let inputs = self.prepare_views(views)?;
+        let input_size = self.input_dimensions(&inputs[0]);
+        let (scores, embedding) = self.run_averaged(inputs)?;
+        let mut prediction = build_output(&scores, embedding, &self.labels, thresholds, filter);
+        prediction.input_size = input_size;
+        Ok(prediction)
+    }
+
+    /// Like [`Predictor::predict_raw`] with the scores averaged over
+    /// `views`, as in [`Predictor::predict_averaged`].
+    pub fn predict_raw_averaged(&mut self, views: &[DynamicImage]) -> Result<RawPrediction> {
+        let inputs = self.prepare_views(views)?;
+        let (scores, embedding) = self.run_averaged(inputs)?;
+        Ok(self.raw_prediction(scores, embedding))
+    }
+
+    fn prepare_views(&mut self, views: &[DynamicImage]) -> Result<Vec<Array4<f32>>> {
+        if views.is_empty() {
+            return Err(TaggerError::NoViews);
+        }
+        self.load()?;
+        let preprocessor = self.preprocessor()?;
+        views
+            .iter()
+            .map(|view| preprocessor.prepare(view))
+            .collect()
+    }
+
+    /// Runs `inputs` as one batch, returning their mean scores and the first
+    /// one's embedding.
+    fn run_averaged(&mut self, inputs: Vec<Array4<f32>>) -> Result<(Vec<f32>, Vec<f32>)> {
+        let count = inputs.len() as f32;
+        let mut outputs = self.run_batch(inputs)?.into_iter();
+        let (mut scores, embedding) = outputs.next().expect("one output per input");
+        for (other, _) in outputs {
+            for (total, v) in scores.iter_mut().zip(other) {
+                *total += v;
             }
-        };
-        // ↑ Both borrows of self.model end here; subsequent borrows are safe.
+        }
+        scores.iter_mut().for_each(|v| *v /= count);
+        Ok((scores, embedding))
+    }
+
+    /// Width and height of a prepared `input`, once the model is loaded.
+    fn input_dimensions(&self, input: &Array4<f32>) -> Option<(usize, usize)> {
+        let shape = input.shape();
+        match self.model.as_ref()? {
+            LoadedModel::Wd14 {
+                layout: Layout::Nhwc,
+                ..
+            } => Some((shape[2], shape[1])),
+            LoadedModel::Wd14 {
+                layout: Layout::Nchw,
+                ..
+            }
+            | LoadedModel::DINOv3 { .. } => Some((shape[3], shape[2])),
+        }
+    }
 
-        build_output(
-            &scores,
+    /// Scores every tag in `img` without any thresholding or filtering.
+    pub fn predict_raw(&mut self, img: &DynamicImage) -> Result<RawPrediction> {
+        self.load()?;
+        let input = self.preprocessor()?.prepare(img)?;
+        self.predict_raw_prepared(input)
+    }
+
+    /// Like [`Predictor::predict_raw`], for an input already produced by this
+    /// predictor's [`Preprocessor`].
+    pub fn predict_raw_prepared(&mut self, input: Array4<f32>) -> Result<RawPrediction> {
+        let (scores, embedding) = self.run(input)?;
+        Ok(self.raw_prediction(scores, embedding))
+    }
+
+    fn raw_prediction(&self, scores: Vec<f32>, embedding: Vec<f32>) -> RawPrediction {
+        let tags = self.labels.names.iter().cloned().zip(scores).collect();
+        RawPrediction {
+            tags,
+            rating: self.labels.rating.clone(),
+            general: self.labels.general.clone(),
+            character: self.labels.character.clone(),
             embedding,
-            &self.tag_names,
-            &self.rating_i,
-            &self.general_i,
-            &self.character_i,
-            g_th,
-            g_mcut,
-            c_th,
-            c_mcut,
-            exclude,
+        }
+    }
+
+    /// Runs the model on a prepared input, returning the per-tag
+    /// probabilities and the embedding.
+    fn run(&mut self, input: Array4<f32>) -> Result<(Vec<f32>, Vec<f32>)> {
+        Ok(self
+            .run_batch(vec![input])?
+            .pop()
+            .expect("one output per input"))
+    }
+
+    /// Like [`Predictor::run`] for several inputs. WD14 stacks them into one
+    /// session call; DINOv3 inputs keep their aspect ratio, so may differ in
+    /// shape, and are run one at a time.
+    fn run_batch(&mut self, inputs: Vec<Array4<f32>>) -> Result<Vec<(Vec<f32>, Vec<f32>)>> {
+        self.load()?;
+        if inputs.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let tag_count = self.labels.names.len();
+        match self.model.as_mut().expect("loaded above") {
+            LoadedModel::Wd14 { sessions, .. } => {
+                let n = inputs.len();
+                let views: Vec<_> = inputs.iter().map(|input| input.view()).collect();
+                let batch = ndarray::concatenate(Axis(0), &views)?;
+
+                // Sum each model's probabilities, then average; the embedding
+                // comes from the first model.
+                let mut results: Vec<(Vec<f32>, Vec<f32>)> = vec![];
+                for (m, model) in sessions.iter_mut().enumerate() {
+                    let outputs = if model.half {
+                        let batch = batch.mapv(half::f16::from_f32);
+                        model
+                            .session
+                            .run(ort::inputs![model.input.as_str() => Tensor::from_array(batch)?])?
+                    } else {
+                        model.session.run(
+                            ort::inputs![model.input.as_str() => Tensor::from_array(batch.clone())?],
+                        )?
+                    };
+                    let scores = extract_f32(&outputs[model.scores])?;
+                    let scores = scores.view().into_dimensionality::<Ix2>()?;
+                    if scores.ncols() != tag_count {
+                        return Err(TaggerError::UnsupportedModel(format!(
+                            "WD14: model {} scores {} tags but its tag list has {}",
+                            m + 1,
+                            scores.ncols(),
+                            tag_count
+                        )));
+                    }
+
+                    if m == 0 {
+                        let embeddings: Vec<f32> = match model.embedding {
+                            Some(i) => extract_f32(&outputs[i])?.into_iter().collect(),
+                            None => vec![],
+                        };
+                        let emb_len = embeddings.len() / n;
+                        results = scores
+                            .rows()
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, row)| {
+                                let emb = embeddings[i * emb_len..(i + 1) * emb_len].to_vec();
+                                (row.to_vec(), emb)
+                            })
+                            .collect();
+                    } else {
+                        for ((total, _), row) in results.iter_mut().zip(scores.rows()) {
+                            for (t, v) in total.iter_mut().zip(row) {
+                                *t += v;
+                            }
+                        }
+                    }
+                }
+                let count = sessions.len() as f32;
+                if count > 1.0 {
+                    for (scores, _) in &mut results {
+                        scores.iter_mut().for_each(|v| *v /= count);
+                    }
+                }
+                Ok(results)
+            }
+
+            LoadedModel::DINOv3 { tagger, embedder } => inputs
+                .into_iter()
+                .map(|input| run_dinov3(tagger, embedder.as_mut(), input))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Calls to [`parse_wd14_labels`] on this test's thread.
+        pub(super) static LABEL_PARSES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A protobuf length-delimited field.
+    fn field(number: u64, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        for mut value in [number << 3 | 2, contents.len() as u64] {
+            while value >= 0x80 {
+                out.push(value as u8 | 0x80);
+                value >>= 7;
+            }
+            out.push(value as u8);
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+
+    /// Writes `contents` to a fresh file in the temp directory.
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wd14-tagger-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_wd14_labels_buckets_by_category() {
+        let csv = "tag_id,name,category,count\n\
+                   1,general,9,1\n\
+                   2,sensitive,9,1\n\
+                   3,long_hair,0,5\n\
+                   4,smile,0,4\n\
+                   5,:d,0,3\n\
+                   6,hatsune_miku,4,2\n\
+                   7,some_artist,1,1\n";
+        let rdr = ReaderBuilder::new()
+            .has_headers(true)
+            .from_reader(csv.as_bytes());
+        let labels = parse_wd14_labels(
+            rdr,
+            csv.len() as u64,
+            Path::new("test.csv"),
+            TagStyle::default(),
+            &CategoryMap::default(),
         )
+        .unwrap();
+        assert_eq!(labels.names.len(), 7);
+        assert_eq!(labels.rating, [0, 1]);
+        assert_eq!(labels.general, [2, 3, 4]);
+        assert_eq!(labels.character, [5]);
+    }
+
+    #[test]
+    fn external_data_files_reads_initializer_locations() {
+        let entry = |key: &str, value: &str| {
+            field(
+                13,
+                &[field(1, key.as_bytes()), field(2, value.as_bytes())].concat(),
+            )
+        };
+        let external = field(
+            5,
+            &[
+                field(8, b"weight"),
+                entry("location", "model.onnx_data"),
+                entry("offset", "0"),
+            ]
+            .concat(),
+        );
+        let inline = field(5, &[field(8, b"bias"), field(9, &[0; 300])].concat());
+        // ir_version = 8, then the graph.
+        let model = [
+            vec![0x08, 0x08],
+            field(7, &[external.clone(), inline, external].concat()),
+        ]
+        .concat();
+        let path = temp_file("external.onnx", &model);
+        assert_eq!(external_data_files(&path).unwrap(), ["model.onnx_data"]);
+    }
+
+    #[test]
+    fn external_data_files_rejects_overlong_fields() {
+        // A graph claiming far more bytes than the file has.
+        let mut model = vec![7 << 3 | 2];
+        model.extend_from_slice(&[0xff; 9]);
+        model.push(0x01);
+        let path = temp_file("overlong.onnx", &model);
+        assert!(external_data_files(&path).is_err());
+    }
+
+    #[test]
+    fn letterbox_offset_centres_with_the_odd_pixel_right_and_bottom() {
+        assert_eq!(letterbox_offset(448, 448), (0, 0));
+        assert_eq!(letterbox_offset(300, 448), (74, 0));
+        assert_eq!(letterbox_offset(448, 300), (0, 74));
+        // 3 pixels of padding: 1 above, 2 below.
+        assert_eq!(letterbox_offset(10, 7), (0, 1));
+        assert_eq!(letterbox_offset(7, 10), (1, 0));
+    }
+
+    #[test]
+    fn load_labels_parses_the_csv_once() {
+        let csv = "tag_id,name,category,count\n1,general,9,1\n2,smile,0,1\n";
+        // Only the labels are loaded, so the model bytes are never read.
+        let mut pred = Predictor::new(ModelKind::Wd14).with_model_bytes(b"not onnx".to_vec(), csv);
+        pred.load_labels().unwrap();
+        pred.load_labels().unwrap();
+        assert_eq!(pred.labels().names.len(), 2);
+        assert_eq!(LABEL_PARSES.with(Cell::get), 1);
+    }
+
+    /// `img` after a round trip through PNG, as the CLI would decode it.
+    fn through_png(img: DynamicImage) -> DynamicImage {
+        let mut png = std::io::Cursor::new(vec![]);
+        img.write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        image::load_from_memory(png.get_ref()).unwrap()
+    }
+
+    #[test]
+    fn flatten_to_rgb_replicates_greyscale() {
+        let grey = through_png(DynamicImage::ImageLuma8(ImageBuffer::from_pixel(
+            2,
+            1,
+            image::Luma([90]),
+        )));
+        assert!(matches!(grey, DynamicImage::ImageLuma8(_)));
+        let rgb = flatten_to_rgb(&grey, [255, 0, 0]);
+        assert_eq!(rgb.dimensions(), (2, 1));
+        assert!(rgb.pixels().all(|p| p.0 == [90; 3]));
+    }
+
+    #[test]
+    fn flatten_to_rgb_scales_16_bit_down() {
+        let deep = through_png(DynamicImage::ImageRgb16(ImageBuffer::from_pixel(
+            1,
+            1,
+            Rgb([0, 257 * 128, u16::MAX]),
+        )));
+        assert!(matches!(deep, DynamicImage::ImageRgb16(_)));
+        assert_eq!(
+            flatten_to_rgb(&deep, [0; 3]).get_pixel(0, 0).0,
+            [0, 128, 255]
+        );
+    }
+
+    #[test]
+    fn check_not_empty_rejects_images_without_pixels() {
+        for (width, height) in [(0, 0), (1, 0), (0, 1)] {
+            let err = check_not_empty(&DynamicImage::new_rgb8(width, height)).unwrap_err();
+            let TaggerError::EmptyImage {
+                width: w,
+                height: h,
+            } = err
+            else {
+                panic!("expected EmptyImage, got {:?}", err);
+            };
+            assert_eq!((w, h), (width, height));
+        }
+        check_not_empty(&DynamicImage::new_rgb8(1, 1)).unwrap();
+    }
+
+    #[test]
+    fn mcut_threshold_needs_two_scores() {
+        assert_eq!(mcut_threshold(&mut []), 0.0);
+        assert_eq!(mcut_threshold(&mut [0.9]), 0.0);
+        // The largest gap is between 0.8 and 0.2.
+        assert!((mcut_threshold(&mut [0.2, 0.9, 0.8, 0.1]) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn flatten_to_rgb32f_keeps_16_bit_precision() {
+        // Between two 8-bit levels, which the 8-bit path rounds away.
+        let deep = through_png(DynamicImage::ImageRgb16(ImageBuffer::from_pixel(
+            1,
+            1,
+            Rgb([257 * 100 + 128; 3]),
+        )));
+        let precise = flatten_to_rgb32f(&deep, [0; 3]).get_pixel(0, 0)[0] * 255.0;
+        assert!((precise - 100.5).abs() < 0.01);
+        assert_eq!(flatten_to_rgb(&deep, [0; 3]).get_pixel(0, 0)[0], 100);
+    }
+
+    #[test]
+    fn flatten_to_rgb32f_matches_the_8_bit_path_on_8_bit_images() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(4, 4, |x, y| {
+            image::Rgba([(x * 60) as u8, (y * 60) as u8, 7, 255])
+        }));
+        let bytes = flatten_to_rgb(&img, [0; 3]);
+        let floats = flatten_to_rgb32f(&img, [0; 3]);
+        for (b, f) in bytes.pixels().zip(floats.pixels()) {
+            for c in 0..3 {
+                assert_eq!((f[c] * 255.0).round() as u8, b[c]);
+            }
+        }
+    }
+
+    #[test]
+    fn normalise_tag_keeps_emoticon_underscores() {
+        assert!(is_emoticon("^_^"));
+        assert!(is_emoticon("0_0"));
+        assert!(!is_emoticon("long_hair"));
+        assert_eq!(normalise_tag("^_^"), "^_^");
+        assert_eq!(normalise_tag("0_0"), "0_0");
+        assert_eq!(normalise_tag("long_hair"), "long hair");
+        assert_eq!(normalise_tag("1girl"), "1girl");
+    }
+
+    #[test]
+    fn sort_by_score_breaks_ties_by_tag_order() {
+        let mut tags = [(4, 0.5), (1, 0.9), (3, 0.5), (0, 0.5), (2, 0.9)];
+        sort_by_score(&mut tags);
+        assert_eq!(tags, [(1, 0.9), (2, 0.9), (0, 0.5), (3, 0.5), (4, 0.5)]);
+    }
+
+    #[test]
+    fn flatten_composites_semi_transparent_pngs_over_the_background() {
+        let img = through_png(DynamicImage::ImageRgba8(ImageBuffer::from_fn(
+            3,
+            1,
+            |x, _| image::Rgba([255, 0, 0, [0, 128, 255][x as usize]]),
+        )));
+        assert!(matches!(img, DynamicImage::ImageRgba8(_)));
+        let rgb = flatten_to_rgb(&img, [255, 255, 255]);
+        assert_eq!(rgb.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(rgb.get_pixel(1, 0).0, [255, 127, 127]);
+        assert_eq!(rgb.get_pixel(2, 0).0, [255, 0, 0]);
+
+        let rgb = flatten_to_rgb32f(&img, [0, 0, 255]);
+        let half = 128.0 / 255.0;
+        assert_eq!(rgb.get_pixel(0, 0).0, [0.0, 0.0, 1.0]);
+        for (got, want) in rgb.get_pixel(1, 0).0.iter().zip([half, 0.0, 1.0 - half]) {
+            assert!((got - want).abs() < 1e-6);
+        }
+        assert_eq!(rgb.get_pixel(2, 0).0, [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn apply_threshold_ignores_excluded_tags() {
+        let scores = vec![(0, 0.95), (1, 0.6), (2, 0.5), (3, 0.1)];
+        let keep = |i| i != 0;
+
+        let mut tags = scores.clone();
+        let min = apply_threshold(&mut tags, Threshold::Relative(0.8), keep);
+        assert_eq!(min, Some(0.6 * 0.8));
+        assert_eq!(tags, [(1, 0.6), (2, 0.5)]);
+
+        // Without tag 0, the largest gap is between 0.5 and 0.1.
+        let mut tags = scores.clone();
+        let min = apply_threshold(&mut tags, Threshold::Mcut { floor: 0.0 }, keep);
+        assert!((min.unwrap() - 0.3).abs() < 1e-6);
+        assert_eq!(tags, [(1, 0.6), (2, 0.5)]);
+
+        let mut tags = scores;
+        apply_threshold(&mut tags, Threshold::TopK(1), keep);
+        assert_eq!(tags, [(1, 0.6)]);
     }
 }
@@ -0,0 +1,468 @@
+//! Minimal blocking HTTP/1.1 server exposing `POST /tag`.
+//!
+//! The request body is the raw image bytes; the query string takes the same
+//! threshold options as the CLI (e.g. `/tag?general-threshold=0.5&top-k=20`),
+//! defaulting to the values the server was started with.
+
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use wd14_tagger::{Predictor, TagOrder, MAX_SCORE_PRECISION};
+
+use crate::decode::{decode_image, DecodeOptions};
+use crate::json::{to_json, JsonOutput};
+use crate::{parse_category_threshold, parse_positive, parse_threshold, ThresholdArgs};
+
+/// Request bodies larger than this are rejected with `413`.
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Longest request or header line, line ending included; longer header
+/// lines are rejected with `431`, a longer request line with `400`.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// Most header lines a request may have before it is rejected with `431`.
+const MAX_HEADERS: usize = 100;
+
+/// How long a single read or write on a connection may block.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client has to send the whole request, so one trickling a
+/// byte at a time can't hold a worker forever; `408` after.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Threads handling connections. Inference is serialised anyway, so these
+/// only overlap reading requests and writing responses.
+const WORKERS: usize = 8;
+
+/// Accepted connections that may wait for a free worker; more are turned
+/// away with `503`.
+const QUEUED_CONNECTIONS: usize = 32;
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Response {
+            status: 200,
+            reason: "OK",
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: impl Display) -> Self {
+        Response {
+            status,
+            reason,
+            body: serde_json::json!({ "error": message.to_string() }).to_string(),
+        }
+    }
+}
+
+/// Serves requests on `bind` until the listener fails. Connections are
+/// handled by a fixed pool of [`WORKERS`] threads, with up to
+/// [`QUEUED_CONNECTIONS`] more waiting; inference is serialised through the
+/// mutex.
+pub fn serve(
+    pred: Predictor,
+    bind: &str,
+    defaults: &ThresholdArgs,
+    decode: DecodeOptions,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(bind)?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+
+    let pred = Arc::new(Mutex::new(pred));
+    let defaults = Arc::new(defaults.clone());
+    let (queue, connections) = mpsc::sync_channel::<TcpStream>(QUEUED_CONNECTIONS);
+    let connections = Arc::new(Mutex::new(connections));
+    for _ in 0..WORKERS {
+        let pred = Arc::clone(&pred);
+        let defaults = Arc::clone(&defaults);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || loop {
+            // Release the lock before handling, so other workers can take
+            // the next connection.
+            let next = connections
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .recv();
+            let Ok(stream) = next else {
+                return;
+            };
+            // A panic must not shrink the pool.
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                handle(stream, &pred, &defaults, decode)
+            }));
+            match handled {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::warn!("connection error: {}", e),
+                Err(_) => log::warn!("connection handler panicked"),
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        match queue.try_send(stream) {
+            Ok(()) => {}
+            Err(TrySendError::Full(mut stream)) => {
+                log::warn!("too many connections; turning one away");
+                let busy = Response::error(503, "Service Unavailable", "too many connections");
+                if let Err(e) = stream
+                    .set_write_timeout(Some(IO_TIMEOUT))
+                    .and_then(|()| write_response(&mut stream, &busy))
+                {
+                    log::warn!("connection error: {}", e);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => unreachable!("workers never hang up"),
+        }
+    }
+    Ok(())
+}
+
+fn handle(
+    mut stream: TcpStream,
+    pred: &Mutex<Predictor>,
+    defaults: &ThresholdArgs,
+    decode: DecodeOptions,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(Deadline {
+        inner: stream.try_clone()?,
+        until: Instant::now() + REQUEST_TIMEOUT,
+    });
+    let response = match read_request(&mut reader, &mut stream) {
+        Ok(Ok(request)) => route(&request, pred, defaults, decode),
+        Ok(Err(response)) => response,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Response::error(408, "Request Timeout", "timed out reading the request")
+        }
+        Err(e) => return Err(e),
+    };
+    write_response(&mut stream, &response)
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// A reader that fails with [`io::ErrorKind::TimedOut`] once `until` has
+/// passed, bounding the total time spent reading rather than each read.
+struct Deadline<R> {
+    inner: R,
+    until: Instant,
+}
+
+impl<R: Read> Read for Deadline<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() >= self.until {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Reads a line of at most [`MAX_LINE_BYTES`] into `line`, returning
+/// whether it fit.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    reader
+        .by_ref()
+        .take(MAX_LINE_BYTES as u64 + 1)
+        .read_line(line)?;
+    Ok(line.len() <= MAX_LINE_BYTES)
+}
+
+/// Reads one request. The outer `Err` is a broken connection; the inner one
+/// is a malformed request that still deserves an error response.
+fn read_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> io::Result<Result<Request, Response>> {
+    let mut line = String::new();
+    if !read_line(reader, &mut line)? {
+        return Ok(Err(Response::error(
+            400,
+            "Bad Request",
+            format!("request line exceeds {} bytes", MAX_LINE_BYTES),
+        )));
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => {
+            return Ok(Err(Response::error(
+                400,
+                "Bad Request",
+                "malformed request line",
+            )))
+        }
+    };
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    let mut headers = 0;
+    loop {
+        let fits = read_line(reader, &mut line)?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if !fits || headers > MAX_HEADERS {
+            return Ok(Err(Response::error(
+                431,
+                "Request Header Fields Too Large",
+                format!(
+                    "headers are limited to {} lines of {} bytes",
+                    MAX_HEADERS, MAX_LINE_BYTES
+                ),
+            )));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = match value.parse() {
+                Ok(len) => len,
+                Err(_) => {
+                    return Ok(Err(Response::error(
+                        400,
+                        "Bad Request",
+                        "invalid Content-Length",
+                    )))
+                }
+            };
+        } else if name.eq_ignore_ascii_case("expect") && value.eq_ignore_ascii_case("100-continue")
+        {
+            expect_continue = true;
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Ok(Err(Response::error(
+            413,
+            "Payload Too Large",
+            format!("request body exceeds {} bytes", MAX_BODY_BYTES),
+        )));
+    }
+    if expect_continue {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Ok(Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        body,
+    }))
+}
+
+fn route(
+    request: &Request,
+    pred: &Mutex<Predictor>,
+    defaults: &ThresholdArgs,
+    decode: DecodeOptions,
+) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/tag") => tag(request, pred, defaults, decode),
+        (_, "/tag") => Response::error(405, "Method Not Allowed", "use POST /tag"),
+        _ => Response::error(404, "Not Found", format!("no route for {}", request.path)),
+    }
+}
+
+fn tag(
+    request: &Request,
+    pred: &Mutex<Predictor>,
+    defaults: &ThresholdArgs,
+    decode: DecodeOptions,
+) -> Response {
+    let thresholds = match thresholds_from_query(&request.query, defaults) {
+        Ok(thresholds) => thresholds,
+        Err(e) => return Response::error(400, "Bad Request", e),
+    };
+    let img = match decode_image(&request.body, decode) {
+        Ok(img) => img,
+        Err(e) => {
+            return Response::error(
+                400,
+                "Bad Request",
+                format!("body is not a valid image: {}", e),
+            )
+        }
+    };
+
+    // A panic in another request must not take the server down with it.
+    let mut pred = pred.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match thresholds.predict(&mut pred, &img) {
+        Ok(prediction) => Response::ok(to_json(&JsonOutput::new(&prediction, None))),
+        Err(e) => Response::error(500, "Internal Server Error", e),
+    }
+}
+
+/// Applies `key=value` query overrides on top of the server defaults. A bare
+/// `key` (no `=`) turns a boolean option on.
+fn thresholds_from_query(query: &str, defaults: &ThresholdArgs) -> Result<ThresholdArgs, String> {
+    let mut thresholds = defaults.clone();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, "true"));
+        let (key, value) = (percent_decode(key)?, percent_decode(value)?);
+        let (key, value) = (key.as_str(), value.as_str());
+        let invalid = |e: String| format!("{}: {}", key, e);
+        match key {
+            "rating-threshold" => {
+                thresholds.rating_threshold = Some(parse_threshold(value).map_err(invalid)?)
+            }
+            "general-threshold" => {
+                thresholds.general_threshold = parse_threshold(value).map_err(invalid)?
+            }
+            "character-threshold" => {
+                thresholds.character_threshold = parse_threshold(value).map_err(invalid)?
+            }
+            "no-general" => thresholds.no_general = parse_bool(value).map_err(invalid)?,
+            "no-characters" => thresholds.no_characters = parse_bool(value).map_err(invalid)?,
+            "general-mcut" => thresholds.general_mcut = parse_bool(value).map_err(invalid)?,
+            "character-mcut" => thresholds.character_mcut = parse_bool(value).map_err(invalid)?,
+            "character-mcut-floor" => {
+                thresholds.character_mcut_floor = parse_threshold(value).map_err(invalid)?
+            }
+            "threshold-per-category" => {
+                for entry in value.split(',') {
+                    let entry = parse_category_threshold(entry).map_err(invalid)?;
+                    thresholds.threshold_per_category.push(entry);
+                }
+            }
+            "softmax-ratings" => thresholds.softmax_ratings = parse_bool(value).map_err(invalid)?,
+            "temperature" => thresholds.temperature = parse_positive(value).map_err(invalid)?,
+            "sort" => thresholds.sort = TagOrder::from_str(value, false).map_err(invalid)?,
+            "relative-threshold" => {
+                thresholds.relative_threshold = Some(parse_threshold(value).map_err(invalid)?)
+            }
+            "score-precision" => {
+                thresholds.score_precision = Some(parse_precision(value).map_err(invalid)?)
+            }
+            "top-k" => thresholds.top_k = Some(parse_count(value).map_err(invalid)?),
+            "max-general" => thresholds.max_general = Some(parse_count(value).map_err(invalid)?),
+            "max-character" => {
+                thresholds.max_character = Some(parse_count(value).map_err(invalid)?)
+            }
+            "min-tags" => thresholds.min_tags = Some(parse_count(value).map_err(invalid)?),
+            _ => return Err(format!("unknown query parameter '{}'", key)),
+        }
+    }
+    Ok(thresholds)
+}
+
+/// Decodes the `%XX` escapes and `+` (a space) of a query key or value.
+fn percent_decode(s: &str) -> Result<String, String> {
+    let invalid = || format!("'{}' has an invalid %-escape", s);
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2).ok_or_else(invalid)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                rest = &rest[2..];
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("'{}' isn't UTF-8 once decoded", s))
+}
+
+fn parse_precision(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(decimals) if decimals <= MAX_SCORE_PRECISION => Ok(decimals),
+        _ => Err(format!(
+            "'{}' is not a number of decimal places from 0 to {}",
+            value, MAX_SCORE_PRECISION
+        )),
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{}' is not a count", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(format!("'{}' is not a boolean", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_handles_escapes_and_plus() {
+        assert_eq!(percent_decode("long%20hair").unwrap(), "long hair");
+        assert_eq!(percent_decode("long+hair").unwrap(), "long hair");
+        assert_eq!(percent_decode("%5E_%5e").unwrap(), "^_^");
+        assert!(percent_decode("50%").is_err());
+        assert!(percent_decode("%zz").is_err());
+        assert!(percent_decode("%ff").is_err());
+    }
+
+    #[test]
+    fn parse_precision_rejects_values_that_break_rounding() {
+        assert_eq!(parse_precision("0"), Ok(0));
+        assert_eq!(parse_precision("9"), Ok(9));
+        assert!(parse_precision("10").is_err());
+        assert!(parse_precision("4294967295").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! `--threshold-sweep`: the general tags one image keeps at several
+//! thresholds, from a single inference.
+
+use serde_json::{Map, Value};
+use wd14_tagger::{RawPrediction, Threshold, ThresholdConfig};
+
+use crate::json::{to_json, to_json_line, TagScore};
+use crate::{OutputFormat, ThresholdArgs};
+
+/// Prints, for each of `sweep`, how many general tags score above it and
+/// which. The other options in `thresholds` apply as usual; only the general
+/// threshold is swept.
+pub fn print(raw: &RawPrediction, sweep: &[f32], thresholds: &ThresholdArgs, format: OutputFormat) {
+    let config = thresholds.config();
+    let predictions = sweep.iter().map(|&min| {
+        let config = ThresholdConfig {
+            general: Threshold::Fixed(min),
+            ..config
+        };
+        (
+            min,
+            thresholds.finish(raw.threshold(&config, &thresholds.filter)),
+        )
+    });
+
+    if format == OutputFormat::Text {
+        println!("{:<9} {:>5}  general", "threshold", "tags");
+        for (min, prediction) in predictions {
+            println!(
+                "{:<9.2} {:>5}  {}",
+                min,
+                prediction.general.len(),
+                prediction.general_string
+            );
+        }
+        return;
+    }
+
+    // {"0.3": [{"tag": ..., "score": ...}, ...], ...}
+    let mut map = Map::new();
+    for (min, prediction) in predictions {
+        let tags: Vec<TagScore> = prediction.general.iter().map(TagScore::from).collect();
+        map.insert(
+            min.to_string(),
+            serde_json::to_value(tags).expect("tags serialise"),
+        );
+    }
+    let map = Value::Object(map);
+    match format {
+        OutputFormat::Ndjson => println!("{}", to_json_line(&map)),
+        _ => println!("{}", to_json(&map)),
+    }
+}